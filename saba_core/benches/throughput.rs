@@ -0,0 +1,83 @@
+//! Measures the throughput of the tokenizer and the parser over a baseline document.
+//! Run with `cargo bench -p saba_core`, optionally with `--features hashbrown` to
+//! compare attribute maps.
+use saba_core::renderer::html::parser::HtmlParser;
+use saba_core::renderer::html::token::HtmlTokenizer;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const BASELINE: &str = include_str!("data/baseline.html");
+// the baseline is repeated to make a document large enough to measure
+const REPEAT: usize = 50;
+const WARM_UP_ITERATIONS: usize = 3;
+const ITERATIONS: usize = 20;
+
+/// Runs `f` and returns the average time per run and the number of items `f`
+/// processed in a run.
+fn measure<F: FnMut() -> usize>(mut f: F) -> (Duration, usize) {
+    for _ in 0..WARM_UP_ITERATIONS {
+        black_box(f());
+    }
+
+    let mut items = 0;
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        items = black_box(f());
+    }
+    (start.elapsed() / ITERATIONS as u32, items)
+}
+
+fn report(name: &str, unit: &str, (time, items): (Duration, usize)) {
+    let per_sec = items as f64 / time.as_secs_f64();
+    println!(
+        "{:<16} {:>10.3?}/iter {:>10} {} {:>14.0} {}/sec",
+        name, time, items, unit, per_sec, unit
+    );
+}
+
+/// Tokens per second of `HtmlTokenizer::next`.
+fn bench_tokenizer(html: &str) {
+    let result = measure(|| HtmlTokenizer::new(html.to_string()).count());
+    report("tokenizer", "tokens", result);
+}
+
+/// Nodes per second of `HtmlParser::construct_tree`, including tokenizing.
+fn bench_parser(html: &str) {
+    let result = measure(|| {
+        let window = HtmlParser::new(HtmlTokenizer::new(html.to_string())).construct_tree();
+        let nodes = window.borrow().document().borrow().descendants().count();
+        nodes
+    });
+    report("parser", "nodes", result);
+}
+
+/// Attribute lookups per second through `Element::attribute_map`.
+fn bench_attribute_map(html: &str) {
+    let window = HtmlParser::new(HtmlTokenizer::new(html.to_string())).construct_tree();
+    let elements: Vec<_> = window
+        .borrow()
+        .all_elements()
+        .iter()
+        .filter_map(|e| e.borrow().get_element())
+        .collect();
+    let result = measure(|| {
+        let mut lookups = 0;
+        for element in &elements {
+            let map = element.attribute_map();
+            for name in ["id", "class", "href", "src"] {
+                black_box(map.get(name));
+                lookups += 1;
+            }
+        }
+        lookups
+    });
+    report("attribute_map", "lookups", result);
+}
+
+fn main() {
+    let html = BASELINE.repeat(REPEAT);
+    println!("document: {} bytes", html.len());
+    bench_tokenizer(&html);
+    bench_parser(&html);
+    bench_attribute_map(&html);
+}
@@ -86,9 +86,10 @@ impl HttpResponse {
         self.body.clone()
     }
 
+    /// Returns the value of the header `name`, which is case-insensitive.
     pub fn header_value(&self, name: &str) -> Result<String, String> {
         for h in &self.headers {
-            if h.name == name {
+            if h.name.eq_ignore_ascii_case(name) {
                 return Ok(h.value.clone());
             }
         }
@@ -132,6 +133,7 @@ mod tests {
         assert_eq!(res.reason(), "OK");
         assert_eq!(res.header_value("Date"), Ok("xx xx xx".to_string()));
         assert_eq!(res.header_value("Content-Length"), Ok("42".to_string()));
+        assert_eq!(res.header_value("content-length"), Ok("42".to_string()));
     }
 
     #[test]
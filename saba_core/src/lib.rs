@@ -8,3 +8,37 @@ pub mod error;
 pub mod renderer;
 pub mod browser;
 pub mod utils;
+
+use crate::renderer::dom::node::Window;
+use crate::renderer::html::parser::HtmlParser;
+use crate::renderer::html::token::HtmlTokenizer;
+use alloc::rc::Rc;
+use alloc::string::String;
+use core::cell::RefCell;
+
+/// Tokenizes and parses `html`, and returns the window which has the constructed DOM tree.
+pub fn parse_html(html: String) -> Rc<RefCell<Window>> {
+    let t = HtmlTokenizer::new(html);
+    HtmlParser::new(t).construct_tree()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::dom::node::Node;
+    use alloc::string::ToString;
+
+    #[test]
+    fn test_parse_html() {
+        let html = "<html><head></head><body><p>text</p></body></html>".to_string();
+        let window = parse_html(html.clone());
+
+        let t = HtmlTokenizer::new(html);
+        let expected = HtmlParser::new(t).construct_tree();
+
+        assert!(Node::tree_eq(
+            &expected.borrow().document(),
+            &window.borrow().document()
+        ));
+    }
+}
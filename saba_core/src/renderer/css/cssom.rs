@@ -1,5 +1,7 @@
 use crate::renderer::css::token::CssToken;
 use crate::renderer::css::token::CssTokenizer;
+use crate::renderer::dom::node::Element;
+use alloc::format;
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
 use core::iter::Peekable;
@@ -90,32 +92,38 @@ impl CssParser {
         }
     }
 
+    /// Consumes the tokens before `{` as a selector. Only simple selectors such as
+    /// `p`, `#id`, and `.class` are supported. Others, e.g. `p#id` or `div p`, become
+    /// `UnknownSelector`, which matches nothing.
     fn consume_selector(&mut self) -> Selector {
-        let token = match self.t.next() {
-            Some(t) => t,
-            None => panic!("should have a token but got None"),
-        };
-        match token {
-            CssToken::HashToken(value) => Selector::IdSelector(value[1..].to_string()),
-            CssToken::Delim(delim) => {
-                if delim == '.' {
-                    return Selector::ClassSelector(self.consume_ident());
+        let mut selectors = Vec::new();
+        while self.t.peek().is_some_and(|t| *t != CssToken::OpenCurly) {
+            let token = self.t.next().expect("should have a token");
+            let selector = match token {
+                CssToken::HashToken(value) if value.len() > 1 => {
+                    Selector::IdSelector(value[1..].to_string())
                 }
-                panic!("Parser error: {:?} is an unexpected token.", token);
-            }
-            CssToken::Ident(ident) => {
-                // treat selecter like `a:hover` as type selector
-                if self.t.peek() == Some(&CssToken::Colon) {
+                CssToken::Delim('.') if matches!(self.t.peek(), Some(CssToken::Ident(_))) => {
+                    Selector::ClassSelector(self.consume_ident())
+                }
+                CssToken::Ident(ident) => {
+                    // treat selecter like `a:hover` as type selector
                     while self.t.peek() == Some(&CssToken::Colon) {
                         self.t.next();
+                        if matches!(self.t.peek(), Some(CssToken::Ident(_))) {
+                            self.t.next();
+                        }
                     }
+                    // type selectors are case-insensitive in html documents
+                    Selector::TypeSelector(ident.to_ascii_lowercase())
                 }
-                Selector::TypeSelector(ident.to_string())
-            }
-            _ => {
-                self.t.next();
-                Selector::UnknownSelector
-            }
+                _ => Selector::UnknownSelector,
+            };
+            selectors.push(selector);
+        }
+        match selectors.len() {
+            1 => selectors.remove(0),
+            _ => Selector::UnknownSelector,
         }
     }
 
@@ -192,6 +200,81 @@ impl StyleSheet {
     pub fn set_rules(&mut self, rules: Vec<QualifiedRule>) {
         self.rules = rules;
     }
+
+    /// Returns (property, value) pairs of all rules matching `element`.
+    /// Rules are applied in specificity order (type < class < id) and, for the same
+    /// specificity, in source order, so a later declaration overrides an earlier one.
+    pub fn matching_declarations(&self, element: &Element) -> Vec<(String, String)> {
+        let mut rules: Vec<&QualifiedRule> = self
+            .rules
+            .iter()
+            .filter(|r| r.selector.matches(element))
+            .collect();
+        // sort_by_key is stable, so source order is kept for the same specificity
+        rules.sort_by_key(|r| r.selector.specificity());
+
+        let mut declarations: Vec<(String, String)> = Vec::new();
+        for rule in rules {
+            for declaration in &rule.declarations {
+                let value = component_value_to_string(&declaration.value);
+                match declarations
+                    .iter_mut()
+                    .find(|(property, _)| *property == declaration.property)
+                {
+                    Some((_, v)) => *v = value,
+                    None => declarations.push((declaration.property.clone(), value)),
+                }
+            }
+        }
+        declarations
+    }
+}
+
+impl Selector {
+    /// Parses a selector in the same way as the selector of a rule in a style sheet,
+    /// so only simple selectors such as `p`, `#id`, or `.class` are supported.
+    pub fn parse(selector: &str) -> Self {
+        CssParser::new(CssTokenizer::new(selector.to_string())).consume_selector()
+    }
+
+    pub fn matches(&self, element: &Element) -> bool {
+        match self {
+            Selector::TypeSelector(type_name) => element.is(type_name),
+            Selector::ClassSelector(class_name) => match element.get_attribute("class") {
+                Some(classes) => classes.split_ascii_whitespace().any(|c| c == class_name),
+                None => false,
+            },
+            Selector::IdSelector(id_name) => element.get_attribute("id").as_ref() == Some(id_name),
+            Selector::UnknownSelector => false,
+        }
+    }
+
+    /// https://www.w3.org/TR/selectors-4/#specificity-rules
+    fn specificity(&self) -> u32 {
+        match self {
+            Selector::IdSelector(_) => 100,
+            Selector::ClassSelector(_) => 10,
+            Selector::TypeSelector(_) => 1,
+            Selector::UnknownSelector => 0,
+        }
+    }
+}
+
+fn component_value_to_string(value: &ComponentValue) -> String {
+    match value {
+        CssToken::HashToken(s)
+        | CssToken::Ident(s)
+        | CssToken::StringToken(s)
+        | CssToken::AtKeyword(s) => s.clone(),
+        CssToken::Number(n) => format!("{}", n),
+        CssToken::Delim(c) => c.to_string(),
+        CssToken::Colon => ":".to_string(),
+        CssToken::SemiColon => ";".to_string(),
+        CssToken::OpenParenthesis => "(".to_string(),
+        CssToken::CloseParenthesis => ")".to_string(),
+        CssToken::OpenCurly => "{".to_string(),
+        CssToken::CloseCurly => "}".to_string(),
+    }
 }
 
 impl QualifiedRule {
@@ -231,6 +314,7 @@ impl Declaration {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::renderer::html::attribute::Attribute;
     use alloc::vec;
 
     #[test]
@@ -343,4 +427,89 @@ mod tests {
             i += 1;
         }
     }
+
+    #[test]
+    fn test_parse_selector() {
+        assert_eq!(
+            Selector::parse("P"),
+            Selector::TypeSelector("p".to_string())
+        );
+        assert_eq!(
+            Selector::parse("#main"),
+            Selector::IdSelector("main".to_string())
+        );
+        assert_eq!(
+            Selector::parse(" .note "),
+            Selector::ClassSelector("note".to_string())
+        );
+        assert_eq!(Selector::parse("#"), Selector::UnknownSelector);
+        assert_eq!(Selector::parse("p.note"), Selector::UnknownSelector);
+        assert_eq!(Selector::parse("div p"), Selector::UnknownSelector);
+        assert_eq!(Selector::parse("div > *"), Selector::UnknownSelector);
+
+        // same as the selector of a rule
+        for selector in ["p#main", "P", "#main", ".note", "a:hover"] {
+            let t = CssTokenizer::new(format!("{} {{ color: red; }}", selector));
+            let cssom = CssParser::new(t).parse_stylesheet();
+            assert_eq!(
+                Selector::parse(selector),
+                cssom.rules[0].selector,
+                "{}",
+                selector
+            );
+        }
+    }
+
+    #[test]
+    fn test_matching_declarations() {
+        let style =
+            "#main { color: red; font-size: 20; } p { color: blue; margin: 0; }".to_string();
+        let t = CssTokenizer::new(style);
+        let cssom = CssParser::new(t).parse_stylesheet();
+
+        let mut attr = Attribute::new();
+        "id".chars().for_each(|c| attr.add_name(c));
+        "main".chars().for_each(|c| attr.add_value(c));
+        let element = Element::new("p", vec![attr]);
+
+        assert_eq!(
+            cssom.matching_declarations(&element),
+            vec![
+                ("color".to_string(), "red".to_string()),
+                ("margin".to_string(), "0".to_string()),
+                ("font-size".to_string(), "20".to_string()),
+            ]
+        );
+
+        let element = Element::new("p", Vec::new());
+        assert_eq!(
+            cssom.matching_declarations(&element),
+            vec![
+                ("color".to_string(), "blue".to_string()),
+                ("margin".to_string(), "0".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compound_selector_is_not_matched() {
+        let style = "div#main { color: red; } p#main { color: blue; } a:hover { color: green; }"
+            .to_string();
+        let t = CssTokenizer::new(style);
+        let cssom = CssParser::new(t).parse_stylesheet();
+        assert_eq!(cssom.rules[0].selector, Selector::UnknownSelector);
+        assert_eq!(cssom.rules[1].selector, Selector::UnknownSelector);
+        assert_eq!(
+            cssom.rules[2].selector,
+            Selector::TypeSelector("a".to_string())
+        );
+
+        let mut attr = Attribute::new();
+        "id".chars().for_each(|c| attr.add_name(c));
+        "main".chars().for_each(|c| attr.add_value(c));
+        for tag in ["p", "div"] {
+            let element = Element::new(tag, vec![attr.clone()]);
+            assert!(cssom.matching_declarations(&element).is_empty(), "{}", tag);
+        }
+    }
 }
@@ -83,6 +83,9 @@ impl CssTokenizer {
         s.push(self.input[self.pos]);
         loop {
             self.pos += 1;
+            if self.pos >= self.input.len() {
+                break;
+            }
             let c = self.input[self.pos];
             match c {
                 'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => s.push(c),
@@ -149,9 +152,8 @@ impl Iterator for CssTokenizer {
                     self.pos -= 1;
                     t
                 }
-                _ => {
-                    unimplemented!("char {} is not supported yet.", c);
-                }
+                // https://www.w3.org/TR/css-syntax-3/#consume-token
+                _ => CssToken::Delim(c),
             };
 
             self.pos += 1;
@@ -230,6 +232,19 @@ mod tests {
         assert!(t.next().is_none());
     }
 
+    #[test]
+    fn test_end_of_input() {
+        let style = "p>*#id".to_string();
+        let t = CssTokenizer::new(style);
+        let expected = [
+            CssToken::Ident("p".to_string()),
+            CssToken::Delim('>'),
+            CssToken::Delim('*'),
+            CssToken::HashToken("#id".to_string()),
+        ];
+        assert_eq!(t.collect::<Vec<_>>(), expected);
+    }
+
     #[test]
     fn test_multiple_rules() {
         let style = "p { content: \"Hey\"; } h1 { font-size: 40; color: blue; }".to_string();
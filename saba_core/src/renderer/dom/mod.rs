@@ -1 +1,3 @@
 pub mod node;
+#[cfg(test)]
+pub(crate) mod testing;
@@ -1,15 +1,29 @@
+use crate::renderer::css::cssom::Selector;
 use crate::renderer::html::attribute::Attribute;
+use alloc::collections::BTreeMap;
 use alloc::format;
 use alloc::rc::Rc;
 use alloc::rc::Weak;
-use alloc::string::String;
+use alloc::string::{String, ToString};
 use alloc::vec::Vec;
 use core::cell::RefCell;
+use core::cmp::Ordering;
+use core::fmt;
+use core::hash::{Hash, Hasher};
+use core::ops::Range;
+use core::ptr;
 use core::str::FromStr;
 
 type WeakRefCell<T> = Weak<RefCell<T>>;
 type RcRefCell<T> = Rc<RefCell<T>>;
 
+/// Map from attribute names to values. This is a `hashbrown::HashMap` with the
+/// `hashbrown` feature, which is faster for elements with many attributes.
+#[cfg(feature = "hashbrown")]
+pub type AttributeMap = hashbrown::HashMap<String, String>;
+#[cfg(not(feature = "hashbrown"))]
+pub type AttributeMap = BTreeMap<String, String>;
+
 #[derive(Debug, Clone)]
 pub struct Node {
     pub kind: NodeKind,
@@ -21,6 +35,8 @@ pub struct Node {
     last_child: WeakRefCell<Node>,
     previous_sibling: WeakRefCell<Node>,
     next_sibling: Option<RcRefCell<Node>>,
+    // contents of <template>, which are not a part of the tree
+    template_content: Option<RcRefCell<Node>>,
 }
 
 impl PartialEq for Node {
@@ -29,6 +45,37 @@ impl PartialEq for Node {
     }
 }
 
+/// A reference to a node which is compared, ordered and hashed by identity
+/// (pointer) instead of by its kind, e.g. for a set of visited nodes.
+#[derive(Debug, Clone)]
+pub struct NodeRef(pub RcRefCell<Node>);
+
+impl PartialEq for NodeRef {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for NodeRef {}
+
+impl Hash for NodeRef {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        ptr::hash(Rc::as_ptr(&self.0), state);
+    }
+}
+
+impl Ord for NodeRef {
+    fn cmp(&self, other: &Self) -> Ordering {
+        Rc::as_ptr(&self.0).cmp(&Rc::as_ptr(&other.0))
+    }
+}
+
+impl PartialOrd for NodeRef {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 impl Node {
     pub fn new(kind: NodeKind) -> Self {
         Self {
@@ -39,6 +86,7 @@ impl Node {
             last_child: Weak::new(),
             previous_sibling: Weak::new(),
             next_sibling: None,
+            template_content: None,
         }
     }
 
@@ -74,6 +122,11 @@ impl Node {
         self.last_child.clone()
     }
 
+    /// Returns the last child if it is still alive.
+    pub fn last_child_rc(&self) -> Option<RcRefCell<Node>> {
+        self.last_child.upgrade()
+    }
+
     pub fn set_previous_sibling(&mut self, sibling: WeakRefCell<Node>) {
         self.previous_sibling = sibling;
     }
@@ -82,6 +135,11 @@ impl Node {
         self.previous_sibling.clone()
     }
 
+    /// Returns the previous sibling if it is still alive, like `next_sibling`.
+    pub fn previous_sibling_rc(&self) -> Option<RcRefCell<Node>> {
+        self.previous_sibling.upgrade()
+    }
+
     pub fn set_next_sibling(&mut self, sibling: Option<RcRefCell<Node>>) {
         self.next_sibling = sibling;
     }
@@ -90,50 +148,715 @@ impl Node {
         self.next_sibling.as_ref().cloned()
     }
 
+    /// The first child which is an element, skipping text and comment nodes.
+    /// https://dom.spec.whatwg.org/#dom-parentnode-firstelementchild
+    pub fn first_element_child(&self) -> Option<RcRefCell<Node>> {
+        let mut child = self.first_child();
+        while let Some(c) = child {
+            if c.borrow().get_element().is_some() {
+                return Some(c);
+            }
+            child = c.borrow().next_sibling();
+        }
+        None
+    }
+
+    /// The last child which is an element, skipping text and comment nodes.
+    /// https://dom.spec.whatwg.org/#dom-parentnode-lastelementchild
+    pub fn last_element_child(&self) -> Option<RcRefCell<Node>> {
+        let mut child = self.last_child_rc();
+        while let Some(c) = child {
+            if c.borrow().get_element().is_some() {
+                return Some(c);
+            }
+            child = c.borrow().previous_sibling_rc();
+        }
+        None
+    }
+
+    pub fn set_template_content(&mut self, content: Option<RcRefCell<Node>>) {
+        self.template_content = content;
+    }
+
+    pub fn template_content(&self) -> Option<RcRefCell<Node>> {
+        self.template_content.as_ref().cloned()
+    }
+
     pub fn get_element(&self) -> Option<Element> {
         match self.kind {
-            NodeKind::Document | NodeKind::Text(_) => None,
+            NodeKind::Document
+            | NodeKind::DocumentFragment
+            | NodeKind::Doctype { .. }
+            | NodeKind::Comment(_)
+            | NodeKind::ProcessingInstruction { .. }
+            | NodeKind::Text(_) => None,
             NodeKind::Element(ref e) => Some(e.clone()),
         }
     }
 
     pub fn element_kind(&self) -> Option<ElementKind> {
         match self.kind {
-            NodeKind::Document | NodeKind::Text(_) => None,
+            NodeKind::Document
+            | NodeKind::DocumentFragment
+            | NodeKind::Doctype { .. }
+            | NodeKind::Comment(_)
+            | NodeKind::ProcessingInstruction { .. }
+            | NodeKind::Text(_) => None,
             NodeKind::Element(ref e) => Some(e.kind()),
         }
     }
+
+    /// Lowercase tag name of the element, or None if this node is not an element.
+    pub fn tag_name(&self) -> Option<String> {
+        self.element_kind().map(|k| k.to_string())
+    }
+
+    /// https://dom.spec.whatwg.org/#dom-node-nodename
+    pub fn node_name(&self) -> String {
+        match self.kind {
+            NodeKind::Document => "#document".to_string(),
+            NodeKind::DocumentFragment => "#document-fragment".to_string(),
+            NodeKind::Doctype { ref name } => name.clone(),
+            NodeKind::Comment(_) => "#comment".to_string(),
+            NodeKind::ProcessingInstruction { ref target, .. } => target.clone(),
+            NodeKind::Element(ref e) => e.kind().to_string().to_ascii_uppercase(),
+            NodeKind::Text(_) => "#text".to_string(),
+        }
+    }
+
+    pub fn is_element(&self, kind: ElementKind) -> bool {
+        self.element_kind() == Some(kind)
+    }
+
+    pub fn is_text(&self) -> bool {
+        matches!(self.kind, NodeKind::Text(_))
+    }
+
+    /// Returns true if `a` and `b` are the same node, not just nodes of the same kind.
+    pub fn ptr_eq(a: &RcRefCell<Node>, b: &RcRefCell<Node>) -> bool {
+        Rc::ptr_eq(a, b)
+    }
+
+    /// Compares the subtrees rooted at `a` and `b` structurally, including text
+    /// contents and attributes which `PartialEq` for `Node` ignores.
+    pub fn tree_eq(a: &RcRefCell<Node>, b: &RcRefCell<Node>) -> bool {
+        let same_kind = match (&a.borrow().kind, &b.borrow().kind) {
+            (NodeKind::Document, NodeKind::Document) => true,
+            (NodeKind::DocumentFragment, NodeKind::DocumentFragment) => true,
+            (NodeKind::Doctype { name: n1 }, NodeKind::Doctype { name: n2 }) => n1 == n2,
+            (NodeKind::Element(e1), NodeKind::Element(e2)) => e1 == e2,
+            (NodeKind::Text(s1), NodeKind::Text(s2)) => s1 == s2,
+            (NodeKind::Comment(s1), NodeKind::Comment(s2)) => s1 == s2,
+            (
+                NodeKind::ProcessingInstruction {
+                    target: t1,
+                    data: d1,
+                },
+                NodeKind::ProcessingInstruction {
+                    target: t2,
+                    data: d2,
+                },
+            ) => t1 == t2 && d1 == d2,
+            _ => false,
+        };
+        if !same_kind {
+            return false;
+        }
+
+        let a = a.borrow();
+        let b = b.borrow();
+        let mut children_a = linked_children(&a);
+        let mut children_b = linked_children(&b);
+        loop {
+            match (children_a.next(), children_b.next()) {
+                (Some(ca), Some(cb)) => {
+                    if !Node::tree_eq(&ca, &cb) {
+                        return false;
+                    }
+                }
+                (None, None) => return true,
+                _ => return false,
+            }
+        }
+    }
+
+    /// https://dom.spec.whatwg.org/#dom-node-comparedocumentposition
+    /// Returns `Less` if `a` precedes `b` in document order, including when `a` is an
+    /// ancestor of `b`, and `Greater` if `a` follows `b`. Nodes in different trees are
+    /// ordered consistently by their roots, though the order has no meaning.
+    pub fn compare_document_position(a: &RcRefCell<Node>, b: &RcRefCell<Node>) -> Ordering {
+        if Rc::ptr_eq(a, b) {
+            return Ordering::Equal;
+        }
+
+        // paths from the root to the nodes
+        let path = |node: &RcRefCell<Node>| {
+            let mut path: Vec<RcRefCell<Node>> = node.borrow().ancestors().collect();
+            path.reverse();
+            path.push(node.clone());
+            path
+        };
+        let path_a = path(a);
+        let path_b = path(b);
+        if !Rc::ptr_eq(&path_a[0], &path_b[0]) {
+            return NodeRef(path_a[0].clone()).cmp(&NodeRef(path_b[0].clone()));
+        }
+
+        let i = path_a
+            .iter()
+            .zip(path_b.iter())
+            .position(|(x, y)| !Rc::ptr_eq(x, y));
+        let i = match i {
+            Some(i) => i,
+            // one is an ancestor of the other
+            None => return path_a.len().cmp(&path_b.len()),
+        };
+
+        // `path_a[i]` and `path_b[i]` are siblings
+        let mut sibling = path_a[i].borrow().next_sibling();
+        while let Some(s) = sibling {
+            if Rc::ptr_eq(&s, &path_b[i]) {
+                return Ordering::Less;
+            }
+            sibling = s.borrow().next_sibling();
+        }
+        Ordering::Greater
+    }
+
+    /// Puts `new` at the position of `old` in the tree. `new` is removed from its
+    /// current position first, and `old` is detached from the tree.
+    pub fn replace_with(old: &RcRefCell<Node>, new: &RcRefCell<Node>) {
+        if Rc::ptr_eq(old, new) {
+            return;
+        }
+        Self::detach(new);
+
+        let parent = match old.borrow().parent().upgrade() {
+            Some(p) => p,
+            None => return,
+        };
+        let previous = old.borrow().previous_sibling_rc();
+        let next = old.borrow().next_sibling();
+
+        match previous {
+            Some(ref p) => p.borrow_mut().set_next_sibling(Some(new.clone())),
+            None => parent.borrow_mut().set_first_child(Some(new.clone())),
+        }
+        match next {
+            Some(ref n) => n.borrow_mut().set_previous_sibling(Rc::downgrade(new)),
+            None => parent.borrow_mut().set_last_child(Rc::downgrade(new)),
+        }
+
+        let mut n = new.borrow_mut();
+        n.set_parent(Rc::downgrade(&parent));
+        n.set_previous_sibling(previous.as_ref().map_or(Weak::new(), Rc::downgrade));
+        n.set_next_sibling(next);
+
+        let mut o = old.borrow_mut();
+        o.set_parent(Weak::new());
+        o.set_previous_sibling(Weak::new());
+        o.set_next_sibling(None);
+    }
+
+    /// Removes `node` from its parent and siblings.
+    fn detach(node: &RcRefCell<Node>) {
+        let parent = match node.borrow().parent().upgrade() {
+            Some(p) => p,
+            None => return,
+        };
+        let previous = node.borrow().previous_sibling_rc();
+        let next = node.borrow().next_sibling();
+
+        match previous {
+            Some(ref p) => p.borrow_mut().set_next_sibling(next.clone()),
+            None => parent.borrow_mut().set_first_child(next.clone()),
+        }
+        match next {
+            Some(ref n) => n
+                .borrow_mut()
+                .set_previous_sibling(previous.as_ref().map_or(Weak::new(), Rc::downgrade)),
+            None => parent
+                .borrow_mut()
+                .set_last_child(previous.as_ref().map_or(Weak::new(), Rc::downgrade)),
+        }
+
+        let mut n = node.borrow_mut();
+        n.set_parent(Weak::new());
+        n.set_previous_sibling(Weak::new());
+        n.set_next_sibling(None);
+    }
+
+    /// Iterates over the parent, grandparent, ... up to the document.
+    pub fn ancestors(&self) -> Ancestors {
+        Ancestors {
+            next: self.parent().upgrade(),
+        }
+    }
+
+    /// Number of ancestors, e.g. 0 for the document and 1 for the root element.
+    pub fn depth(&self) -> usize {
+        self.ancestors().count()
+    }
+
+    /// Iterates over all descendants in pre-order, not including this node.
+    pub fn descendants(&self) -> Descendants {
+        let first_child = self.first_child();
+        let next = linked_children(self).next();
+        Descendants {
+            cycle_detected: first_child.is_some() && next.is_none(),
+            next,
+            depth: 1,
+        }
+    }
+
+    /// Concatenated text of this node and all of its descendants.
+    pub fn text_content(&self) -> String {
+        if let NodeKind::Text(ref s) = self.kind {
+            return s.clone();
+        }
+
+        let mut text = String::new();
+        for node in self.descendants() {
+            if let NodeKind::Text(ref s) = node.borrow().kind {
+                text.push_str(s);
+            }
+        }
+        text
+    }
+
+    /// Returns true if this node is an element matching `selector`, which is a
+    /// simple selector such as `p`, `#id`, or `.class`.
+    /// https://dom.spec.whatwg.org/#dom-element-matches
+    pub fn matches(&self, selector: &str) -> bool {
+        match self.kind {
+            NodeKind::Element(ref e) => Selector::parse(selector).matches(e),
+            _ => false,
+        }
+    }
+
+    /// Text of this node and its descendants as rendered, similar to `innerText`.
+    /// Runs of white space are collapsed into one space, and a line break is
+    /// inserted at the boundaries of block elements.
+    /// https://html.spec.whatwg.org/multipage/dom.html#the-innertext-idl-attribute
+    pub fn inner_text(&self) -> String {
+        let mut text = InnerText::default();
+        text.append(self);
+        text.text
+    }
+
+    /// Same as `inner_text`, but hints the semantics of elements in a markdown-like
+    /// format for accessibility, e.g. `# heading`, `[link](href)`, and `![alt]`.
+    pub fn serialize_text_only(&self) -> String {
+        let mut text = InnerText {
+            annotate: true,
+            ..Default::default()
+        };
+        text.append(self);
+        text.text
+    }
+
+    /// Serializes this node and its descendants as html.
+    /// https://html.spec.whatwg.org/multipage/parsing.html#serialising-html-fragments
+    pub fn outer_html(&self) -> String {
+        let mut html = String::new();
+        self.write_html(&mut html)
+            .expect("writing to a String should not fail");
+        html
+    }
+
+    /// Serializes the children of this node as html. The contents of a `<template>`
+    /// are serialized as its children.
+    pub fn inner_html(&self) -> String {
+        let mut html = String::new();
+        serialize_children(self, &mut html).expect("writing to a String should not fail");
+        html
+    }
+
+    /// Same as `outer_html`, but writes the html into `out` piece by piece instead of
+    /// building one large `String`.
+    pub fn write_html(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        serialize_node(self, false, out)
+    }
+}
+
+/// Escapes `&`, `<` and `>` in a text node so that it's not parsed as markup.
+pub fn escape_html_text(text: &str) -> String {
+    let mut escaped = String::new();
+    write_escaped_text(text, &mut escaped).expect("writing to a String should not fail");
+    escaped
+}
+
+fn write_escaped_text(text: &str, out: &mut impl fmt::Write) -> fmt::Result {
+    for c in text.chars() {
+        match c {
+            '&' => out.write_str("&amp;")?,
+            '<' => out.write_str("&lt;")?,
+            '>' => out.write_str("&gt;")?,
+            _ => out.write_char(c)?,
+        }
+    }
+    Ok(())
+}
+
+// `raw_text` is true for the text in raw text elements such as `<style>`, which is
+// not escaped.
+fn serialize_node(node: &Node, raw_text: bool, out: &mut impl fmt::Write) -> fmt::Result {
+    match node.kind {
+        NodeKind::Document | NodeKind::DocumentFragment => serialize_children(node, out),
+        NodeKind::Doctype { ref name } => write!(out, "<!DOCTYPE {}>", name),
+        NodeKind::Element(ref e) => {
+            write!(out, "<{}", e.kind())?;
+            for attr in &e.attributes {
+                out.write_str(&attr.to_html())?;
+            }
+            out.write_char('>')?;
+            if e.kind().is_void() {
+                return Ok(());
+            }
+            serialize_children(node, out)?;
+            write!(out, "</{}>", e.kind())
+        }
+        NodeKind::Text(ref s) if raw_text => out.write_str(s),
+        NodeKind::Text(ref s) => write_escaped_text(s, out),
+        NodeKind::Comment(ref s) => write!(out, "<!--{}-->", s),
+        NodeKind::ProcessingInstruction {
+            ref target,
+            ref data,
+        } => write_processing_instruction(target, data, out),
+    }
+}
+
+/// Writes `<?target data?>`, or `<?target?>` if `data` is empty.
+fn write_processing_instruction(
+    target: &str,
+    data: &str,
+    out: &mut impl fmt::Write,
+) -> fmt::Result {
+    if data.is_empty() {
+        write!(out, "<?{}?>", target)
+    } else {
+        write!(out, "<?{} {}?>", target, data)
+    }
+}
+
+fn serialize_children(node: &Node, out: &mut impl fmt::Write) -> fmt::Result {
+    let raw_text = matches!(
+        node.element_kind(),
+        Some(ElementKind::Style | ElementKind::Script)
+    );
+    if let Some(content) = node.template_content() {
+        return serialize_children(&content.borrow(), out);
+    }
+    for c in linked_children(node) {
+        serialize_node(&c.borrow(), raw_text, out)?;
+    }
+    Ok(())
+}
+
+/// Iterates over the children of `parent`, but stops at a child whose parent or
+/// previous sibling link doesn't point back. Such a tree is broken and may have a
+/// cycle, which would make a traversal loop forever.
+fn linked_children(parent: &Node) -> impl Iterator<Item = RcRefCell<Node>> + '_ {
+    let first_child = parent
+        .first_child()
+        .filter(|c| is_linked(&c.borrow(), parent, None));
+    core::iter::successors(first_child, move |previous| {
+        previous
+            .borrow()
+            .next_sibling()
+            .filter(|c| is_linked(&c.borrow(), parent, Some(previous)))
+    })
+}
+
+fn is_linked(child: &Node, parent: &Node, previous: Option<&RcRefCell<Node>>) -> bool {
+    let parent_linked = child
+        .parent()
+        .upgrade()
+        .is_some_and(|p| ptr::eq(p.as_ptr(), parent));
+    let previous_linked = match (child.previous_sibling_rc(), previous) {
+        (Some(a), Some(b)) => Rc::ptr_eq(&a, b),
+        (None, None) => true,
+        _ => false,
+    };
+    parent_linked && previous_linked
+}
+
+#[derive(Default)]
+struct InnerText {
+    text: String,
+    pending_space: bool,
+    pending_break: bool,
+    // true to hint the semantics of headings, links, and images
+    annotate: bool,
+}
+
+impl InnerText {
+    fn append(&mut self, node: &Node) {
+        let kind = match node.kind {
+            NodeKind::Text(ref s) => {
+                self.push_str(s);
+                return;
+            }
+            NodeKind::Element(ref e) => Some(e.kind()),
+            _ => None,
+        };
+        match kind {
+            // not rendered
+            Some(ElementKind::Head | ElementKind::Style | ElementKind::Script) => return,
+            Some(ElementKind::Br) => {
+                self.text.push('\n');
+                self.pending_space = false;
+                self.pending_break = false;
+                return;
+            }
+            _ => {}
+        }
+
+        let is_block = matches!(
+            kind,
+            Some(
+                ElementKind::Html
+                    | ElementKind::Body
+                    | ElementKind::P
+                    | ElementKind::H1
+                    | ElementKind::H2
+                    | ElementKind::Div
+                    | ElementKind::Pre
+                    | ElementKind::Table
+                    | ElementKind::Tbody
+                    | ElementKind::Tr
+            )
+        );
+        self.pending_break |= is_block;
+        let element = node.get_element().filter(|_| self.annotate);
+        match kind {
+            Some(ElementKind::H1) if self.annotate => self.push_str("# "),
+            Some(ElementKind::H2) if self.annotate => self.push_str("## "),
+            Some(ElementKind::A) if self.annotate => self.push('['),
+            Some(ElementKind::Img) => {
+                if let Some(alt) = element.as_ref().and_then(|e| e.get_attribute("alt")) {
+                    self.push_str("![");
+                    self.push_str(&alt);
+                    self.push(']');
+                }
+            }
+            _ => {}
+        }
+        for c in linked_children(node) {
+            self.append(&c.borrow());
+        }
+        if kind == Some(ElementKind::A) && self.annotate {
+            self.push(']');
+            if let Some(href) = element.and_then(|e| e.get_attribute("href")) {
+                self.push('(');
+                self.push_str(&href);
+                self.push(')');
+            }
+        }
+        self.pending_break |= is_block;
+    }
+
+    fn push_str(&mut self, s: &str) {
+        s.chars().for_each(|c| self.push(c));
+    }
+
+    fn push(&mut self, c: char) {
+        if c.is_ascii_whitespace() {
+            self.pending_space = true;
+            return;
+        }
+        if !self.text.is_empty() && !self.text.ends_with('\n') {
+            if self.pending_break {
+                self.text.push('\n');
+            } else if self.pending_space {
+                self.text.push(' ');
+            }
+        }
+        self.pending_space = false;
+        self.pending_break = false;
+        self.text.push(c);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Ancestors {
+    next: Option<RcRefCell<Node>>,
+}
+
+impl Iterator for Ancestors {
+    type Item = RcRefCell<Node>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let current = self.next.take()?;
+        self.next = current.borrow().parent().upgrade();
+        Some(current)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Descendants {
+    next: Option<RcRefCell<Node>>,
+    // depth of `next` from the node where the iteration started
+    depth: usize,
+    cycle_detected: bool,
+}
+
+impl Descendants {
+    /// Returns true if the iteration stopped at a node whose links don't point back,
+    /// i.e. the tree is broken and may have a cycle.
+    pub fn cycle_detected(&self) -> bool {
+        self.cycle_detected
+    }
+
+    /// Yields each node with its depth from the node where the iteration started,
+    /// e.g. 1 for its children.
+    pub fn with_depth(mut self) -> impl Iterator<Item = (RcRefCell<Node>, usize)> {
+        core::iter::from_fn(move || {
+            let depth = self.depth;
+            self.next().map(|n| (n, depth))
+        })
+    }
+}
+
+impl Iterator for Descendants {
+    type Item = RcRefCell<Node>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let current = self.next.take()?;
+
+        let first_child = current.borrow().first_child();
+        if let Some(child) = first_child {
+            if is_linked(&child.borrow(), &current.borrow(), None) {
+                self.next = Some(child);
+                self.depth += 1;
+            } else {
+                self.cycle_detected = true;
+            }
+            return Some(current);
+        }
+
+        // go up until a node which has a next sibling is found,
+        // but never beyond the node where the iteration started
+        let mut node = current.clone();
+        while self.depth > 0 {
+            let parent = match node.borrow().parent().upgrade() {
+                Some(p) => p,
+                None => break,
+            };
+            let sibling = node.borrow().next_sibling();
+            if let Some(sibling) = sibling {
+                if is_linked(&sibling.borrow(), &parent.borrow(), Some(&node)) {
+                    self.next = Some(sibling);
+                } else {
+                    self.cycle_detected = true;
+                }
+                break;
+            }
+            node = parent;
+            self.depth -= 1;
+        }
+        Some(current)
+    }
 }
 
 #[derive(Debug, Clone, Eq)]
 pub enum NodeKind {
     Document,
+    DocumentFragment,
+    Doctype {
+        name: String,
+    },
     Element(Element),
     Text(String),
+    Comment(String),
+    /// https://dom.spec.whatwg.org/#interface-processinginstruction
+    ProcessingInstruction {
+        target: String,
+        data: String,
+    },
 }
 
 impl PartialEq for NodeKind {
     fn eq(&self, other: &Self) -> bool {
         match &self {
             NodeKind::Document => matches!(other, NodeKind::Document),
+            NodeKind::DocumentFragment => matches!(other, NodeKind::DocumentFragment),
+            NodeKind::Doctype { .. } => matches!(other, NodeKind::Doctype { .. }),
             NodeKind::Element(e1) => match &other {
                 NodeKind::Element(e2) => e1.kind == e2.kind,
                 _ => false,
             },
             NodeKind::Text(_) => matches!(other, NodeKind::Text(_)),
+            NodeKind::Comment(_) => matches!(other, NodeKind::Comment(_)),
+            NodeKind::ProcessingInstruction { .. } => {
+                matches!(other, NodeKind::ProcessingInstruction { .. })
+            }
+        }
+    }
+}
+
+fn write_debug_tree(node: &RcRefCell<Node>, depth: usize, tree: &mut String) {
+    tree.push_str(&"  ".repeat(depth));
+    match node.borrow().kind {
+        NodeKind::Document | NodeKind::DocumentFragment => {
+            tree.push_str(&node.borrow().node_name())
+        }
+        NodeKind::Doctype { ref name } => tree.push_str(&format!("<!DOCTYPE {}>", name)),
+        NodeKind::Element(ref e) => {
+            tree.push_str(&format!("<{}", e.kind()));
+            for attr in &e.attributes {
+                tree.push_str(&format!(" {}={:?}", attr.name(), attr.value()));
+            }
+            tree.push('>');
         }
+        NodeKind::Text(ref s) => tree.push_str(&format!("{:?}", s)),
+        NodeKind::Comment(ref s) => tree.push_str(&format!("<!--{}-->", s)),
+        NodeKind::ProcessingInstruction {
+            ref target,
+            ref data,
+        } => write_processing_instruction(target, data, tree)
+            .expect("writing to a String should not fail"),
+    }
+    tree.push('\n');
+
+    if let Some(content) = node.borrow().template_content() {
+        write_debug_tree(&content, depth + 1, tree);
+    }
+    for c in linked_children(&node.borrow()) {
+        write_debug_tree(&c, depth + 1, tree);
     }
 }
 
+/// https://dom.spec.whatwg.org/#concept-document-mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QuirksMode {
+    #[default]
+    NoQuirks,
+    Quirks,
+    LimitedQuirks,
+}
+
 #[derive(Debug, Clone)]
 pub struct Window {
     document: RcRefCell<Node>,
+    quirks_mode: QuirksMode,
+}
+
+/// Two windows are equal if their documents are structurally equal. The back
+/// reference from each node to its window is not followed.
+impl PartialEq for Window {
+    fn eq(&self, other: &Self) -> bool {
+        Node::tree_eq(&self.document, &other.document)
+    }
 }
 
 impl Window {
     pub fn new() -> Self {
         let document = Rc::new(RefCell::new(Node::new(NodeKind::Document)));
-        let window = Self { document };
+        let window = Self {
+            document,
+            quirks_mode: QuirksMode::default(),
+        };
 
         window
             .document
@@ -146,29 +869,250 @@ impl Window {
     pub fn document(&self) -> RcRefCell<Node> {
         self.document.clone()
     }
+
+    pub fn quirks_mode(&self) -> QuirksMode {
+        self.quirks_mode
+    }
+
+    pub fn set_quirks_mode(&mut self, mode: QuirksMode) {
+        self.quirks_mode = mode;
+    }
+
+    /// The root element of the document, i.e. `document.documentElement`.
+    pub fn document_element(&self) -> Option<RcRefCell<Node>> {
+        let mut child = self.document.borrow().first_child();
+        while let Some(c) = child {
+            if c.borrow().get_element().is_some() {
+                return Some(c);
+            }
+            child = c.borrow().next_sibling();
+        }
+        None
+    }
+
+    /// Compact and deterministic representation of the tree for assertions in
+    /// tests. Each node is on its own line indented by its depth, e.g. `<p id="x">`
+    /// for an element and `"hi"` for a text.
+    pub fn to_debug_tree(&self) -> String {
+        let mut tree = String::new();
+        write_debug_tree(&self.document, 0, &mut tree);
+        tree
+    }
+
+    /// `href` of the first `<base>` element which has it. The value is not resolved
+    /// against the document url.
+    /// https://html.spec.whatwg.org/multipage/urls-and-fetching.html#document-base-url
+    pub fn base_url(&self) -> Option<String> {
+        self.document
+            .borrow()
+            .descendants()
+            .filter(|n| n.borrow().is_element(ElementKind::Base))
+            .find_map(|n| n.borrow().get_element()?.get_attribute("href"))
+    }
+
+    /// Text of the first `<title>` element with white space stripped and collapsed.
+    /// https://html.spec.whatwg.org/multipage/dom.html#document.title
+    pub fn title(&self) -> String {
+        let title = self
+            .document
+            .borrow()
+            .descendants()
+            .find(|n| n.borrow().is_element(ElementKind::Title));
+        match title {
+            Some(title) => title
+                .borrow()
+                .text_content()
+                .split_ascii_whitespace()
+                .collect::<Vec<_>>()
+                .join(" "),
+            None => String::new(),
+        }
+    }
+
+    /// Text contents of all `<style>` elements in document order.
+    pub fn style_sheets(&self) -> Vec<String> {
+        self.document
+            .borrow()
+            .descendants()
+            .filter(|n| n.borrow().is_element(ElementKind::Style))
+            .map(|n| n.borrow().text_content())
+            .collect()
+    }
+
+    /// Depth of the deepest node in the document, or 0 if the document is empty.
+    pub fn max_depth(&self) -> usize {
+        self.document
+            .borrow()
+            .descendants()
+            .with_depth()
+            .map(|(_, depth)| depth)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// All elements in the document in document order.
+    pub fn all_elements(&self) -> Vec<RcRefCell<Node>> {
+        self.document
+            .borrow()
+            .descendants()
+            .filter(|n| n.borrow().get_element().is_some())
+            .collect()
+    }
+
+    /// All elements satisfying `f` in document order.
+    pub fn elements_where<F: Fn(&Element) -> bool>(&self, f: F) -> Vec<RcRefCell<Node>> {
+        self.document
+            .borrow()
+            .descendants()
+            .filter(|n| match n.borrow().kind {
+                NodeKind::Element(ref e) => f(e),
+                _ => false,
+            })
+            .collect()
+    }
+
+    /// https://html.spec.whatwg.org/multipage/dom.html#dom-document-getelementsbyname
+    pub fn get_elements_by_name(&self, name: &str) -> Vec<RcRefCell<Node>> {
+        self.elements_where(|e| e.get_attribute("name").as_deref() == Some(name))
+    }
+
+    /// https://dom.spec.whatwg.org/#dom-document-getelementsbyclassname
+    /// This implementation only supports a single class name.
+    pub fn get_elements_by_class_name(&self, class: &str) -> Vec<RcRefCell<Node>> {
+        self.elements_where(|e| e.class_list().iter().any(|c| c == class))
+    }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Eq)]
 pub struct Element {
     kind: ElementKind,
     attributes: Vec<Attribute>,
+    // byte range in the source html. only recorded when the parser is asked to
+    source_range: Option<Range<usize>>,
+}
+
+impl PartialEq for Element {
+    fn eq(&self, other: &Self) -> bool {
+        // the source range is not a part of the element itself
+        self.kind == other.kind && self.attributes == other.attributes
+    }
 }
 
 impl Element {
+    /// Creates an element. Tag names which this implementation doesn't know become
+    /// `ElementKind::Unknown` elements.
     pub fn new(element_name: &str, attributes: Vec<Attribute>) -> Self {
+        let kind = ElementKind::from_str(element_name)
+            .unwrap_or_else(|_| ElementKind::Unknown(element_name.to_string()));
+        Self::with_kind(kind, attributes)
+    }
+
+    /// Creates an element, or returns an error if `element_name` is not known.
+    pub fn try_new(element_name: &str, attributes: Vec<Attribute>) -> Result<Self, String> {
+        let kind = ElementKind::from_str(element_name)?;
+        Ok(Self::with_kind(kind, attributes))
+    }
+
+    fn with_kind(kind: ElementKind, attributes: Vec<Attribute>) -> Self {
         Self {
-            kind: ElementKind::from_str(element_name)
-                .expect("Failed to convert string to ElementKind"),
+            kind,
             attributes,
+            source_range: None,
         }
     }
 
     pub fn kind(&self) -> ElementKind {
-        self.kind
+        self.kind.clone()
+    }
+
+    /// Returns true if this element has the tag name `tag`, compared ASCII
+    /// case-insensitively. Unlike `ElementKind::from_str`, a tag name which this
+    /// implementation doesn't know is not an error and matches only an unknown
+    /// element of the same name.
+    pub fn is(&self, tag: &str) -> bool {
+        let tag = tag.to_ascii_lowercase();
+        match ElementKind::from_str(&tag) {
+            Ok(kind) => self.kind == kind,
+            Err(_) => self.kind == ElementKind::Unknown(tag),
+        }
+    }
+
+    pub fn attributes(&self) -> Vec<Attribute> {
+        self.attributes.clone()
+    }
+
+    pub fn get_attribute(&self, name: &str) -> Option<String> {
+        self.attributes
+            .iter()
+            .find(|a| a.eq_name(name))
+            .map(|a| a.value())
+    }
+
+    /// Updates the value of the first attribute named `name`, or appends a new
+    /// attribute if there is none.
+    pub fn set_attribute(&mut self, name: &str, value: &str) {
+        if let Some(attr) = self.attributes.iter_mut().find(|a| a.eq_name(name)) {
+            attr.set_value(value);
+            return;
+        }
+
+        let mut attr = Attribute::new();
+        name.chars().for_each(|c| attr.add_name(c));
+        attr.set_value(value);
+        self.attributes.push(attr);
+    }
+
+    /// Class names in the `class` attribute.
+    pub fn class_list(&self) -> Vec<String> {
+        match self.get_attribute("class") {
+            Some(classes) => classes
+                .split_ascii_whitespace()
+                .map(|c| c.to_string())
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Values of `data-*` attributes keyed by the name without the `data-` prefix.
+    /// Unlike `HTMLElement.dataset`, keys are not converted to camel case.
+    pub fn dataset(&self) -> BTreeMap<String, String> {
+        self.attributes
+            .iter()
+            .filter_map(|a| {
+                let key = a.name().strip_prefix("data-")?.to_string();
+                Some((key, a.value()))
+            })
+            .collect()
+    }
+
+    /// Attributes keyed by name. If names are repeated, the first one wins as in
+    /// `get_attribute`.
+    pub fn attribute_map(&self) -> AttributeMap {
+        let mut map = AttributeMap::new();
+        for attr in &self.attributes {
+            map.entry(attr.name()).or_insert_with(|| attr.value());
+        }
+        map
+    }
+
+    /// Byte range `[start, end)` of the source html this element was parsed from.
+    pub fn source_range(&self) -> Option<Range<usize>> {
+        self.source_range.clone()
+    }
+
+    pub fn set_source_range(&mut self, range: Range<usize>) {
+        self.source_range = Some(range);
+    }
+
+    /// Removes the first attribute named `name`.
+    pub fn remove_attribute(&mut self, name: &str) {
+        if let Some(i) = self.attributes.iter().position(|a| a.eq_name(name)) {
+            self.attributes.remove(i);
+        }
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ElementKind {
     Html,
     Head,
@@ -179,23 +1123,975 @@ pub enum ElementKind {
     H1,
     H2,
     A,
+    Table,
+    Tbody,
+    Tr,
+    Td,
+    Th,
+    Select,
+    Option,
+    Template,
+    Noscript,
+    Br,
+    Div,
+    Img,
+    Title,
+    Pre,
+    Base,
+    Colgroup,
+    Col,
+    Caption,
+    Form,
+    /// An element this implementation doesn't know, with its original tag name.
+    Unknown(String),
+}
+
+impl fmt::Display for ElementKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            ElementKind::Html => "html",
+            ElementKind::Head => "head",
+            ElementKind::Style => "style",
+            ElementKind::Script => "script",
+            ElementKind::Body => "body",
+            ElementKind::P => "p",
+            ElementKind::H1 => "h1",
+            ElementKind::H2 => "h2",
+            ElementKind::A => "a",
+            ElementKind::Table => "table",
+            ElementKind::Tbody => "tbody",
+            ElementKind::Tr => "tr",
+            ElementKind::Td => "td",
+            ElementKind::Th => "th",
+            ElementKind::Select => "select",
+            ElementKind::Option => "option",
+            ElementKind::Template => "template",
+            ElementKind::Noscript => "noscript",
+            ElementKind::Br => "br",
+            ElementKind::Div => "div",
+            ElementKind::Img => "img",
+            ElementKind::Title => "title",
+            ElementKind::Pre => "pre",
+            ElementKind::Base => "base",
+            ElementKind::Colgroup => "colgroup",
+            ElementKind::Col => "col",
+            ElementKind::Caption => "caption",
+            ElementKind::Form => "form",
+            ElementKind::Unknown(name) => name,
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl ElementKind {
+    /// Converts an ASCII tag name in bytes without UTF-8 validation or allocation.
+    pub fn from_bytes(b: &[u8]) -> Result<Self, String> {
+        match b {
+            b"html" => Ok(ElementKind::Html),
+            b"head" => Ok(ElementKind::Head),
+            b"style" => Ok(ElementKind::Style),
+            b"script" => Ok(ElementKind::Script),
+            b"body" => Ok(ElementKind::Body),
+            b"p" => Ok(ElementKind::P),
+            b"h1" => Ok(ElementKind::H1),
+            b"h2" => Ok(ElementKind::H2),
+            b"a" => Ok(ElementKind::A),
+            b"table" => Ok(ElementKind::Table),
+            b"tbody" => Ok(ElementKind::Tbody),
+            b"tr" => Ok(ElementKind::Tr),
+            b"td" => Ok(ElementKind::Td),
+            b"th" => Ok(ElementKind::Th),
+            b"select" => Ok(ElementKind::Select),
+            b"option" => Ok(ElementKind::Option),
+            b"template" => Ok(ElementKind::Template),
+            b"noscript" => Ok(ElementKind::Noscript),
+            b"br" => Ok(ElementKind::Br),
+            b"div" => Ok(ElementKind::Div),
+            b"img" => Ok(ElementKind::Img),
+            b"title" => Ok(ElementKind::Title),
+            b"pre" => Ok(ElementKind::Pre),
+            b"base" => Ok(ElementKind::Base),
+            b"colgroup" => Ok(ElementKind::Colgroup),
+            b"col" => Ok(ElementKind::Col),
+            b"caption" => Ok(ElementKind::Caption),
+            b"form" => Ok(ElementKind::Form),
+            _ => Err(format!(
+                "Unimplemented element name {:?}",
+                String::from_utf8_lossy(b)
+            )),
+        }
+    }
+
+    /// Returns true if the element can't have any contents.
+    /// https://html.spec.whatwg.org/multipage/syntax.html#void-elements
+    pub fn is_void(&self) -> bool {
+        matches!(
+            self,
+            ElementKind::Br | ElementKind::Img | ElementKind::Base | ElementKind::Col
+        )
+    }
+
+    /// Returns true if the element is in the special category used by the scope checks.
+    /// https://html.spec.whatwg.org/multipage/parsing.html#special
+    pub fn is_special(&self) -> bool {
+        matches!(
+            self,
+            ElementKind::Html
+                | ElementKind::Head
+                | ElementKind::Style
+                | ElementKind::Script
+                | ElementKind::Body
+                | ElementKind::P
+                | ElementKind::H1
+                | ElementKind::H2
+                | ElementKind::Table
+                | ElementKind::Tbody
+                | ElementKind::Tr
+                | ElementKind::Td
+                | ElementKind::Th
+                | ElementKind::Select
+                | ElementKind::Template
+                | ElementKind::Noscript
+                | ElementKind::Br
+                | ElementKind::Div
+                | ElementKind::Img
+                | ElementKind::Title
+                | ElementKind::Pre
+                | ElementKind::Base
+                | ElementKind::Colgroup
+                | ElementKind::Col
+                | ElementKind::Caption
+                | ElementKind::Form
+        )
+    }
 }
 
 impl FromStr for ElementKind {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "html" => Ok(ElementKind::Html),
-            "head" => Ok(ElementKind::Head),
-            "style" => Ok(ElementKind::Style),
-            "script" => Ok(ElementKind::Script),
-            "body" => Ok(ElementKind::Body),
-            "p" => Ok(ElementKind::P),
-            "h1" => Ok(ElementKind::H1),
-            "h2" => Ok(ElementKind::H2),
-            "a" => Ok(ElementKind::A),
-            _ => Err(format!("Unimplemented element name {:?}", s)),
+        Self::from_bytes(s.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::dom::testing::NodeBuilder;
+    use crate::renderer::html::parser::HtmlParser;
+    use crate::renderer::html::token::HtmlTokenizer;
+    use alloc::collections::BTreeSet;
+    use alloc::vec;
+
+    #[test]
+    fn test_style_sheets() {
+        let html = "<html><head><style>p { color: red; }</style><script>var a;</script><style>h1 { color: blue; }</style></head><body></body></html>".to_string();
+        let t = HtmlTokenizer::new(html);
+        let window = HtmlParser::new(t).construct_tree();
+
+        assert_eq!(
+            window.borrow().style_sheets(),
+            vec![
+                "p { color: red; }".to_string(),
+                "h1 { color: blue; }".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_to_debug_tree() {
+        let html = "<html><body><p id=x>hi</p></body></html>".to_string();
+        let window = HtmlParser::new(HtmlTokenizer::new(html)).construct_tree();
+        assert_eq!(
+            window.borrow().to_debug_tree(),
+            "#document
+  <html>
+    <head>
+    <body>
+      <p id=\"x\">
+        \"hi\"
+"
+        );
+
+        let html = "<!DOCTYPE html><template><a href='a\"b'>x\ny</a></template>".to_string();
+        let window = HtmlParser::new(HtmlTokenizer::new(html)).construct_tree();
+        assert_eq!(
+            window.borrow().to_debug_tree(),
+            "#document
+  <!DOCTYPE html>
+  <html>
+    <head>
+      <template>
+        #document-fragment
+          <a href=\"a\\\"b\">
+            \"x\\ny\"
+"
+        );
+    }
+
+    #[test]
+    fn test_escape_html_text() {
+        assert_eq!(escape_html_text("a < b & c > d"), "a &lt; b &amp; c &gt; d");
+        assert_eq!(escape_html_text("\"quoted\""), "\"quoted\"");
+    }
+
+    #[test]
+    fn test_outer_html() {
+        let html = "<html><head><!--c--><style>p > a {}</style></head><body><p class=\"a\">x<br>y</p></body></html>";
+        let window = HtmlParser::new(HtmlTokenizer::new(html.to_string())).construct_tree();
+        let document = window.borrow().document();
+        assert_eq!(document.borrow().outer_html(), html);
+
+        let body = document
+            .borrow()
+            .descendants()
+            .find(|n| n.borrow().is_element(ElementKind::Body));
+        let body = body.expect("failed to find body");
+        assert_eq!(body.borrow().inner_html(), "<p class=\"a\">x<br>y</p>");
+    }
+
+    #[test]
+    fn test_write_html() {
+        let html =
+            "<html><head><title>a &amp; b</title></head><body><p id=\"x\">1<br>2</p></body></html>";
+        let window = HtmlParser::new(HtmlTokenizer::new(html.to_string())).construct_tree();
+        let document = window.borrow().document();
+
+        let mut out = String::new();
+        document
+            .borrow()
+            .write_html(&mut out)
+            .expect("failed to write html");
+        assert_eq!(out, document.borrow().outer_html());
+        assert_eq!(out, html);
+    }
+
+    #[test]
+    fn test_escape_text_round_trip() {
+        let html = "<html><head></head><body><p>a &lt; b &amp; c</p></body></html>";
+        let window = HtmlParser::new(HtmlTokenizer::new(html.to_string())).construct_tree();
+        let document = window.borrow().document();
+        let p = document
+            .borrow()
+            .descendants()
+            .find(|n| n.borrow().is_element(ElementKind::P));
+        assert_eq!(
+            p.expect("failed to find p").borrow().text_content(),
+            "a < b & c"
+        );
+
+        let serialized = document.borrow().outer_html();
+        assert_eq!(serialized, html);
+        let reparsed = HtmlParser::new(HtmlTokenizer::new(serialized)).construct_tree();
+        assert_eq!(*window.borrow(), *reparsed.borrow());
+    }
+
+    #[test]
+    fn test_astral_characters_round_trip() {
+        for (html, serialized) in [
+            ("<p>smile \u{1f600}!</p>", "<p>smile \u{1f600}!</p>"),
+            ("<p>smile &#x1F600;!</p>", "<p>smile \u{1f600}!</p>"),
+        ] {
+            let window = HtmlParser::new(HtmlTokenizer::new(html.to_string())).construct_tree();
+            let document = window.borrow().document();
+            let p = document
+                .borrow()
+                .descendants()
+                .find(|n| n.borrow().is_element(ElementKind::P))
+                .expect("failed to find p");
+            assert_eq!(p.borrow().text_content(), "smile \u{1f600}!");
+            assert_eq!(p.borrow().outer_html(), serialized);
+
+            let reparsed = HtmlParser::new(HtmlTokenizer::new(document.borrow().outer_html()))
+                .construct_tree();
+            assert_eq!(*window.borrow(), *reparsed.borrow());
+        }
+    }
+
+    #[test]
+    fn test_window_eq() {
+        let html = "<html><head><title>t</title></head><body><p class=a>x</p></body></html>";
+        let window1 = HtmlParser::new(HtmlTokenizer::new(html.to_string())).construct_tree();
+        let window2 = HtmlParser::new(HtmlTokenizer::new(html.to_string())).construct_tree();
+        assert_eq!(*window1.borrow(), *window2.borrow());
+
+        let html = "<html><head><title>t</title></head><body><p class=b>x</p></body></html>";
+        let window3 = HtmlParser::new(HtmlTokenizer::new(html.to_string())).construct_tree();
+        assert_ne!(*window1.borrow(), *window3.borrow());
+    }
+
+    #[test]
+    fn test_base_url() {
+        let html = "<head><base target=_top><base href=\"http://cdn/\"><base href=x></head>";
+        let window = HtmlParser::new(HtmlTokenizer::new(html.to_string())).construct_tree();
+        assert_eq!(window.borrow().base_url(), Some("http://cdn/".to_string()));
+
+        let window = HtmlParser::new(HtmlTokenizer::new("<p>a</p>".to_string())).construct_tree();
+        assert_eq!(window.borrow().base_url(), None);
+    }
+
+    #[test]
+    fn test_title() {
+        let html = "<head><title>\n  a &amp;  <b>\n</title></head><p>c</p>".to_string();
+        let window = HtmlParser::new(HtmlTokenizer::new(html)).construct_tree();
+        assert_eq!(window.borrow().title(), "a & <b>");
+
+        let html = "<p>no title</p>".to_string();
+        let window = HtmlParser::new(HtmlTokenizer::new(html)).construct_tree();
+        assert_eq!(window.borrow().title(), "");
+    }
+
+    #[test]
+    fn test_element_try_new() {
+        let element = Element::try_new("p", Vec::new()).expect("p should be known");
+        assert_eq!(element.kind(), ElementKind::P);
+        assert!(Element::try_new("my-widget", Vec::new()).is_err());
+        assert_eq!(
+            Element::new("my-widget", Vec::new()).kind(),
+            ElementKind::Unknown("my-widget".to_string())
+        );
+    }
+
+    #[test]
+    fn test_is_element() {
+        let node = Node::new(NodeKind::Element(Element::new("p", Vec::new())));
+        assert!(node.is_element(ElementKind::P));
+        assert!(!node.is_element(ElementKind::A));
+        assert!(!node.is_text());
+    }
+
+    #[test]
+    fn test_node_name() {
+        assert_eq!(Node::new(NodeKind::Document).node_name(), "#document");
+        assert_eq!(
+            Node::new(NodeKind::DocumentFragment).node_name(),
+            "#document-fragment"
+        );
+        assert_eq!(
+            Node::new(NodeKind::Doctype {
+                name: "html".to_string()
+            })
+            .node_name(),
+            "html"
+        );
+        assert_eq!(
+            Node::new(NodeKind::Element(Element::new("tbody", Vec::new()))).node_name(),
+            "TBODY"
+        );
+        assert_eq!(
+            Node::new(NodeKind::Comment("a".to_string())).node_name(),
+            "#comment"
+        );
+        assert_eq!(
+            Node::new(NodeKind::Text("a".to_string())).node_name(),
+            "#text"
+        );
+    }
+
+    #[test]
+    fn test_is_text() {
+        let node = Node::new(NodeKind::Text("text".to_string()));
+        assert!(node.is_text());
+        assert!(!node.is_element(ElementKind::P));
+    }
+
+    #[test]
+    fn test_rc_helpers() {
+        let parent = NodeBuilder::element("p")
+            .child(NodeBuilder::text("a"))
+            .child(NodeBuilder::text("b"))
+            .build();
+        let a = parent.borrow().first_child().expect("a should exist");
+        let b = a.borrow().next_sibling().expect("b should exist");
+
+        let last = parent.borrow().last_child_rc();
+        assert!(last.is_some_and(|n| Rc::ptr_eq(&n, &b)));
+        let previous = b.borrow().previous_sibling_rc();
+        assert!(previous.is_some_and(|n| Rc::ptr_eq(&n, &a)));
+        assert!(a.borrow().previous_sibling_rc().is_none());
+        assert!(a.borrow().last_child_rc().is_none());
+    }
+
+    #[test]
+    fn test_document_element() {
+        let html = "<html><head></head><body></body></html>".to_string();
+        let t = HtmlTokenizer::new(html);
+        let window = HtmlParser::new(t).construct_tree();
+        let html = window
+            .borrow()
+            .document_element()
+            .expect("failed to get a document element");
+        assert!(html.borrow().is_element(ElementKind::Html));
+
+        let t = HtmlTokenizer::new("".to_string());
+        let window = HtmlParser::new(t).construct_tree();
+        assert!(window.borrow().document_element().is_none());
+    }
+
+    #[test]
+    fn test_ancestors() {
+        let document = NodeBuilder::document()
+            .child(
+                NodeBuilder::element("html").child(
+                    NodeBuilder::element("body")
+                        .child(NodeBuilder::element("p").child(NodeBuilder::text("text"))),
+                ),
+            )
+            .build();
+        let html = document.borrow().first_child().expect("html should exist");
+        let body = html.borrow().first_child().expect("body should exist");
+        let p = body.borrow().first_child().expect("p should exist");
+        let text = p.borrow().first_child().expect("text should exist");
+
+        let ancestors: Vec<RcRefCell<Node>> = text.borrow().ancestors().collect();
+        assert_eq!(ancestors.len(), 4);
+        for (ancestor, expected) in ancestors.iter().zip([&p, &body, &html, &document]) {
+            assert!(Rc::ptr_eq(ancestor, expected));
         }
+        assert_eq!(document.borrow().ancestors().count(), 0);
+    }
+
+    #[test]
+    fn test_element_child() {
+        let html = "<div> text <p></p> text <a></a><!--c--> text </div>";
+        let window = HtmlParser::new(HtmlTokenizer::new(html.to_string())).construct_tree();
+        let div = window
+            .borrow()
+            .elements_where(|e| e.kind() == ElementKind::Div)
+            .pop()
+            .expect("failed to find div");
+
+        let first = div.borrow().first_element_child();
+        assert!(first.is_some_and(|n| n.borrow().is_element(ElementKind::P)));
+        let last = div.borrow().last_element_child();
+        assert!(last.is_some_and(|n| n.borrow().is_element(ElementKind::A)));
+
+        let text = div
+            .borrow()
+            .first_child()
+            .expect("div should have a text child");
+        assert!(text.borrow().first_element_child().is_none());
+        assert!(text.borrow().last_element_child().is_none());
+    }
+
+    #[test]
+    fn test_depth() {
+        let document = NodeBuilder::document()
+            .child(
+                NodeBuilder::element("html")
+                    .child(
+                        NodeBuilder::element("head")
+                            .child(NodeBuilder::element("title").child(NodeBuilder::text("t"))),
+                    )
+                    .child(
+                        NodeBuilder::element("body").child(
+                            NodeBuilder::element("p")
+                                .child(NodeBuilder::text("a"))
+                                .child(NodeBuilder::element("a").child(NodeBuilder::text("b"))),
+                        ),
+                    ),
+            )
+            .build();
+        assert_eq!(document.borrow().depth(), 0);
+
+        let depths: Vec<(String, usize)> = document
+            .borrow()
+            .descendants()
+            .map(|n| (n.borrow().node_name(), n.borrow().depth()))
+            .collect();
+        let expected = [
+            ("HTML", 1),
+            ("HEAD", 2),
+            ("TITLE", 3),
+            ("#text", 4),
+            ("BODY", 2),
+            ("P", 3),
+            ("#text", 4),
+            ("A", 4),
+            ("#text", 5),
+        ];
+        assert_eq!(depths.len(), expected.len());
+        for ((name, depth), (expected_name, expected_depth)) in depths.iter().zip(expected) {
+            assert_eq!((name.as_str(), *depth), (expected_name, expected_depth));
+        }
+        let with_depth: Vec<usize> = document
+            .borrow()
+            .descendants()
+            .with_depth()
+            .map(|(_, depth)| depth)
+            .collect();
+        assert_eq!(
+            with_depth,
+            depths.iter().map(|(_, d)| *d).collect::<Vec<_>>()
+        );
+
+        let html = "<html><head><title>t</title></head><body><p>a<a>b</a></p></body></html>";
+        let window = HtmlParser::new(HtmlTokenizer::new(html.to_string())).construct_tree();
+        assert!(Node::tree_eq(&document, &window.borrow().document()));
+        assert_eq!(window.borrow().max_depth(), 5);
+        assert_eq!(Window::new().max_depth(), 0);
+    }
+
+    #[test]
+    fn test_descendants() {
+        let document = NodeBuilder::document()
+            .child(
+                NodeBuilder::element("html")
+                    .child(NodeBuilder::element("head").child(NodeBuilder::element("style")))
+                    .child(
+                        NodeBuilder::element("body")
+                            .child(NodeBuilder::element("p").child(NodeBuilder::text("a")))
+                            .child(NodeBuilder::element("p").child(NodeBuilder::text("b"))),
+                    ),
+            )
+            .build();
+
+        let kinds: Vec<Option<ElementKind>> = document
+            .borrow()
+            .descendants()
+            .map(|n| n.borrow().element_kind())
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                Some(ElementKind::Html),
+                Some(ElementKind::Head),
+                Some(ElementKind::Style),
+                Some(ElementKind::Body),
+                Some(ElementKind::P),
+                None,
+                Some(ElementKind::P),
+                None,
+            ]
+        );
+
+        // iteration stops at the end of the subtree
+        let head = document
+            .borrow()
+            .first_child()
+            .expect("html should exist")
+            .borrow()
+            .first_child()
+            .expect("head should exist");
+        assert_eq!(head.borrow().descendants().count(), 1);
+    }
+
+    #[test]
+    fn test_tag_name() {
+        let node = Node::new(NodeKind::Element(Element::new("h1", Vec::new())));
+        assert_eq!(node.tag_name(), Some("h1".to_string()));
+
+        let node = Node::new(NodeKind::Text("text".to_string()));
+        assert_eq!(node.tag_name(), None);
+    }
+
+    fn build_list() -> (RcRefCell<Node>, Vec<RcRefCell<Node>>) {
+        let parent = NodeBuilder::element("p")
+            .child(NodeBuilder::text("a"))
+            .child(NodeBuilder::element("a"))
+            .child(NodeBuilder::text("c"))
+            .build();
+        let children: Vec<RcRefCell<Node>> = parent.borrow().descendants().collect();
+        (parent, children)
+    }
+
+    fn assert_links(parent: &RcRefCell<Node>, expected: &[&RcRefCell<Node>]) {
+        let first = parent
+            .borrow()
+            .first_child()
+            .expect("first child should exist");
+        let last = parent
+            .borrow()
+            .last_child()
+            .upgrade()
+            .expect("last child should exist");
+        assert!(Rc::ptr_eq(&first, expected[0]));
+        assert!(Rc::ptr_eq(&last, expected[expected.len() - 1]));
+
+        for (i, node) in expected.iter().enumerate() {
+            let n = node.borrow();
+            assert!(Rc::ptr_eq(
+                &n.parent().upgrade().expect("parent should exist"),
+                parent
+            ));
+            match n.previous_sibling().upgrade() {
+                Some(p) => assert!(Rc::ptr_eq(&p, expected[i - 1])),
+                None => assert_eq!(i, 0),
+            }
+            match n.next_sibling() {
+                Some(next) => assert!(Rc::ptr_eq(&next, expected[i + 1])),
+                None => assert_eq!(i, expected.len() - 1),
+            }
+        }
+    }
+
+    fn assert_detached(node: &RcRefCell<Node>) {
+        assert!(node.borrow().parent().upgrade().is_none());
+        assert!(node.borrow().previous_sibling().upgrade().is_none());
+        assert!(node.borrow().next_sibling().is_none());
+    }
+
+    #[test]
+    fn test_replace_first_child() {
+        let (parent, children) = build_list();
+        let new = NodeBuilder::text("new").build();
+        Node::replace_with(&children[0], &new);
+        assert_links(&parent, &[&new, &children[1], &children[2]]);
+        assert_detached(&children[0]);
+    }
+
+    #[test]
+    fn test_replace_middle_child() {
+        let (parent, children) = build_list();
+        let new = NodeBuilder::text("new").build();
+        Node::replace_with(&children[1], &new);
+        assert_links(&parent, &[&children[0], &new, &children[2]]);
+        assert_detached(&children[1]);
+    }
+
+    #[test]
+    fn test_replace_last_child() {
+        let (parent, children) = build_list();
+        let new = NodeBuilder::text("new").build();
+        Node::replace_with(&children[2], &new);
+        assert_links(&parent, &[&children[0], &children[1], &new]);
+        assert_detached(&children[2]);
+    }
+
+    #[test]
+    fn test_replace_only_child() {
+        let parent = NodeBuilder::element("p")
+            .child(NodeBuilder::text("a"))
+            .build();
+        let old = parent.borrow().first_child().expect("child should exist");
+        let new = NodeBuilder::element("a").build();
+        Node::replace_with(&old, &new);
+        assert_links(&parent, &[&new]);
+        assert_detached(&old);
+    }
+
+    #[test]
+    fn test_replace_with_sibling() {
+        let (parent, children) = build_list();
+        // move the last child to the position of the first child
+        Node::replace_with(&children[0], &children[2]);
+        assert_links(&parent, &[&children[2], &children[1]]);
+        assert_detached(&children[0]);
+    }
+
+    #[test]
+    fn test_set_attribute() {
+        let mut element = Element::new("p", Vec::new());
+        element.set_attribute("id", "a");
+        assert_eq!(element.get_attribute("id"), Some("a".to_string()));
+
+        element.set_attribute("class", "b");
+        element.set_attribute("id", "c");
+        assert_eq!(element.get_attribute("id"), Some("c".to_string()));
+        assert_eq!(element.attributes().len(), 2);
+        assert_eq!(element.attributes()[0].name(), "id");
+    }
+
+    #[test]
+    fn test_remove_attribute() {
+        let mut element = Element::new("p", Vec::new());
+        element.set_attribute("id", "a");
+        element.set_attribute("class", "b");
+
+        element.remove_attribute("id");
+        assert_eq!(element.get_attribute("id"), None);
+        assert_eq!(element.get_attribute("class"), Some("b".to_string()));
+
+        // removing a missing attribute does nothing
+        element.remove_attribute("id");
+        assert_eq!(element.attributes().len(), 1);
+    }
+
+    #[test]
+    // NodeRef is ordered by pointer, so mutating a node doesn't change its key
+    #[allow(clippy::mutable_key_type)]
+    fn test_node_ref() {
+        let a = Rc::new(RefCell::new(Node::new(NodeKind::Text("a".to_string()))));
+        let b = Rc::new(RefCell::new(Node::new(NodeKind::Text("a".to_string()))));
+        assert_eq!(a, b);
+        assert!(!Node::ptr_eq(&a, &b));
+        assert!(Node::ptr_eq(&a, &a.clone()));
+
+        let mut visited = BTreeSet::new();
+        visited.insert(NodeRef(a.clone()));
+        visited.insert(NodeRef(b.clone()));
+        visited.insert(NodeRef(a.clone()));
+        assert_eq!(visited.len(), 2);
+        assert!(visited.contains(&NodeRef(b)));
+    }
+
+    #[test]
+    fn test_descendants_with_cycle() {
+        let parent = NodeBuilder::element("p")
+            .child(NodeBuilder::text("a"))
+            .child(NodeBuilder::text("b"))
+            .build();
+        let a = parent.borrow().first_child().expect("a should exist");
+        let b = a.borrow().next_sibling().expect("b should exist");
+        // b -> a makes a sibling cycle
+        b.borrow_mut().set_next_sibling(Some(a.clone()));
+
+        let mut descendants = parent.borrow().descendants();
+        assert_eq!(descendants.by_ref().count(), 2);
+        assert!(descendants.cycle_detected());
+        assert_eq!(parent.borrow().text_content(), "ab");
+        assert_eq!(parent.borrow().outer_html(), "<p>ab</p>");
+        assert_eq!(parent.borrow().inner_text(), "ab");
+        assert!(Node::tree_eq(&parent, &parent));
+
+        // b -> p makes a child cycle
+        b.borrow_mut().set_next_sibling(None);
+        b.borrow_mut().set_first_child(Some(parent.clone()));
+        let mut descendants = parent.borrow().descendants();
+        assert_eq!(descendants.by_ref().count(), 2);
+        assert!(descendants.cycle_detected());
+        assert_eq!(parent.borrow().outer_html(), "<p>ab</p>");
+
+        // break the cycle to free nodes
+        b.borrow_mut().set_first_child(None);
+    }
+
+    #[test]
+    fn test_inner_text() {
+        let inner_text = |html: &str| {
+            let window = HtmlParser::new(HtmlTokenizer::new(html.to_string())).construct_tree();
+            let body = window
+                .borrow()
+                .all_elements()
+                .into_iter()
+                .find(|n| n.borrow().is_element(ElementKind::Body))
+                .expect("failed to get body");
+            let text = body.borrow().inner_text();
+            text
+        };
+        assert_eq!(inner_text("<div>a</div><div>b</div>"), "a\nb");
+        assert_eq!(inner_text("<p>  a \n\t b  </p><h1>c</h1>"), "a b\nc");
+        assert_eq!(inner_text("<p>a <a>b</a>c</p>"), "a bc");
+        assert_eq!(inner_text("<p>a<br>b</p>"), "a\nb");
+        assert_eq!(inner_text("<style>p{}</style><div>a</div>"), "a");
+    }
+
+    #[test]
+    fn test_serialize_text_only() {
+        let html = "<h1>Title</h1><p>See <a href=/docs>the  docs</a> and <a>this</a>.</p><h2>Logo</h2><p><img src=x alt=\"a logo\"><img src=y></p>";
+        let window = HtmlParser::new(HtmlTokenizer::new(html.to_string())).construct_tree();
+        let document = window.borrow().document();
+        assert_eq!(
+            document.borrow().serialize_text_only(),
+            "# Title\nSee [the docs](/docs) and [this].\n## Logo\n![a logo]"
+        );
+        assert_eq!(
+            document.borrow().inner_text(),
+            "Title\nSee the docs and this.\nLogo"
+        );
+    }
+
+    #[test]
+    fn test_element_kind_is_special() {
+        assert!(ElementKind::P.is_special());
+        assert!(ElementKind::Div.is_special());
+        assert!(ElementKind::Table.is_special());
+        assert!(ElementKind::Html.is_special());
+        assert!(!ElementKind::A.is_special());
+        assert!(!ElementKind::Option.is_special());
+        assert!(!ElementKind::Unknown("b".to_string()).is_special());
+        assert!(!ElementKind::Unknown("span".to_string()).is_special());
+    }
+
+    #[test]
+    fn test_element_kind_from_bytes() {
+        assert_eq!(ElementKind::from_bytes(b"p"), Ok(ElementKind::P));
+        assert_eq!(ElementKind::from_bytes(b"table"), Ok(ElementKind::Table));
+        assert_eq!(
+            ElementKind::from_bytes(b"noscript"),
+            Ok(ElementKind::Noscript)
+        );
+        assert_eq!(ElementKind::from_bytes(b"br"), Ok(ElementKind::Br));
+        assert_eq!(ElementKind::from_bytes(b"div"), Ok(ElementKind::Div));
+        assert!(ElementKind::Br.is_void());
+        assert!(ElementKind::Img.is_void());
+        assert!(!ElementKind::P.is_void());
+        assert!(ElementKind::from_bytes(b"P").is_err());
+        assert!(ElementKind::from_bytes(b"span").is_err());
+        assert!(ElementKind::from_bytes(&[0x70, 0xff]).is_err());
+        assert!(ElementKind::from_bytes("p\u{e9}".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_all_elements() {
+        let html = "<html><head></head><body><p>a<a>b</a></p></body></html>".to_string();
+        let window = HtmlParser::new(HtmlTokenizer::new(html)).construct_tree();
+
+        let tags: Vec<_> = window
+            .borrow()
+            .all_elements()
+            .iter()
+            .filter_map(|n| n.borrow().tag_name())
+            .collect();
+        assert_eq!(tags, ["html", "head", "body", "p", "a"]);
+    }
+
+    #[test]
+    fn test_get_elements_by_class_name() {
+        let html = "<html><body><p class=\"x y\">a</p><p class=xy>b</p><h1 class=\" y\">c</h1></body></html>".to_string();
+        let window = HtmlParser::new(HtmlTokenizer::new(html)).construct_tree();
+
+        let texts: Vec<_> = window
+            .borrow()
+            .get_elements_by_class_name("y")
+            .iter()
+            .map(|n| n.borrow().text_content())
+            .collect();
+        assert_eq!(texts, ["a", "c"]);
+        assert!(window.borrow().get_elements_by_class_name("z").is_empty());
+    }
+
+    #[test]
+    fn test_get_elements_by_name() {
+        let html = "<html><body><select name=a id=1></select><a name=b></a><select name=a id=2></select></body></html>".to_string();
+        let window = HtmlParser::new(HtmlTokenizer::new(html)).construct_tree();
+
+        let ids: Vec<_> = window
+            .borrow()
+            .get_elements_by_name("a")
+            .iter()
+            .filter_map(|n| n.borrow().get_element()?.get_attribute("id"))
+            .collect();
+        assert_eq!(ids, ["1", "2"]);
+        assert!(window.borrow().get_elements_by_name("A").is_empty());
+    }
+
+    #[test]
+    fn test_class_list() {
+        let mut element = Element::new("p", Vec::new());
+        assert!(element.class_list().is_empty());
+        element.set_attribute("class", " a\tb  c ");
+        assert_eq!(element.class_list(), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_matches() {
+        let mut element = Element::new("p", Vec::new());
+        element.set_attribute("id", "main");
+        element.set_attribute("class", "note wide");
+        let node = Node::new(NodeKind::Element(element));
+
+        assert!(node.matches("p"));
+        assert!(!node.matches("a"));
+        assert!(node.matches("#main"));
+        assert!(!node.matches("#other"));
+        assert!(node.matches(".note"));
+        assert!(node.matches(".wide"));
+        assert!(!node.matches(".not"));
+        assert!(!node.matches("p > a"));
+        assert!(!Node::new(NodeKind::Text("p".to_string())).matches("p"));
+    }
+
+    #[test]
+    fn test_elements_where() {
+        let html = "<p id=a>x</p><p id=\"\">y</p><p>z</p><div id=b></div>".to_string();
+        let window = HtmlParser::new(HtmlTokenizer::new(html)).construct_tree();
+        let ids: Vec<_> = window
+            .borrow()
+            .elements_where(|e| e.get_attribute("id").is_some_and(|id| !id.is_empty()))
+            .iter()
+            .filter_map(|n| n.borrow().get_element()?.get_attribute("id"))
+            .collect();
+        assert_eq!(ids, ["a", "b"]);
+
+        let paragraphs = window
+            .borrow()
+            .elements_where(|e| e.kind() == ElementKind::P);
+        assert_eq!(paragraphs.len(), 3);
+    }
+
+    #[test]
+    fn test_dataset() {
+        let html = "<div data-id=\"5\" id=\"d\" data-name=\"x\">a</div>".to_string();
+        let window = HtmlParser::new(HtmlTokenizer::new(html)).construct_tree();
+        let div = window
+            .borrow()
+            .all_elements()
+            .into_iter()
+            .find(|n| n.borrow().is_element(ElementKind::Div))
+            .expect("failed to get div");
+        let dataset = div
+            .borrow()
+            .get_element()
+            .expect("div is an element")
+            .dataset();
+
+        let mut expected = BTreeMap::new();
+        expected.insert("id".to_string(), "5".to_string());
+        expected.insert("name".to_string(), "x".to_string());
+        assert_eq!(dataset, expected);
+    }
+
+    #[test]
+    fn test_element_is() {
+        let p = Element::new("p", Vec::new());
+        assert!(p.is("p"));
+        assert!(p.is("P"));
+        assert!(!p.is("div"));
+        assert!(!p.is("blink"));
+        assert!(!p.is(""));
+
+        let widget = Element::new("my-widget", Vec::new());
+        assert!(widget.is("my-widget"));
+        assert!(!widget.is("p"));
+    }
+
+    #[test]
+    fn test_attribute_map() {
+        let element = NodeBuilder::element("a")
+            .attribute("href", "/x")
+            .attribute("class", "a b")
+            .attribute("href", "/y")
+            .build();
+        let element = element.borrow().get_element().expect("a is an element");
+        let map = element.attribute_map();
+
+        assert_eq!(map.len(), 2);
+        for name in ["href", "class", "id"] {
+            assert_eq!(
+                map.get(name).cloned(),
+                element.get_attribute(name),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn test_compare_document_position() {
+        let parent = NodeBuilder::element("p")
+            .child(NodeBuilder::element("a").child(NodeBuilder::text("x")))
+            .child(NodeBuilder::text("y"))
+            .build();
+        let a = parent.borrow().first_child().expect("a should exist");
+        let x = a.borrow().first_child().expect("x should exist");
+        let y = a.borrow().next_sibling().expect("y should exist");
+
+        // siblings
+        assert_eq!(Node::compare_document_position(&a, &y), Ordering::Less);
+        assert_eq!(Node::compare_document_position(&y, &a), Ordering::Greater);
+        // ancestor and descendant
+        assert_eq!(Node::compare_document_position(&parent, &x), Ordering::Less);
+        assert_eq!(Node::compare_document_position(&x, &a), Ordering::Greater);
+        // descendants of siblings
+        assert_eq!(Node::compare_document_position(&x, &y), Ordering::Less);
+        assert_eq!(Node::compare_document_position(&x, &x), Ordering::Equal);
+
+        // unrelated nodes
+        let other = NodeBuilder::text("z").build();
+        let order = Node::compare_document_position(&x, &other);
+        assert_ne!(order, Ordering::Equal);
+        assert_eq!(Node::compare_document_position(&other, &x), order.reverse());
+        assert_eq!(Node::compare_document_position(&parent, &other), order);
     }
 }
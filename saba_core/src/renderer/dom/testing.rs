@@ -0,0 +1,251 @@
+//! Helpers to build DOM trees by hand, mainly for assertions in tests.
+use crate::renderer::dom::node::{Element, Node, NodeKind};
+use crate::renderer::html::attribute::Attribute;
+use alloc::rc::Rc;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::cell::RefCell;
+
+type RcRefCell<T> = Rc<RefCell<T>>;
+
+#[derive(Debug, Clone)]
+enum BuilderKind {
+    Document,
+    DocumentFragment,
+    Doctype(String),
+    Element {
+        tag: String,
+        attributes: Vec<Attribute>,
+    },
+    Text(String),
+    Comment(String),
+    ProcessingInstruction {
+        target: String,
+        data: String,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct NodeBuilder {
+    kind: BuilderKind,
+    children: Vec<NodeBuilder>,
+}
+
+impl NodeBuilder {
+    pub fn document() -> Self {
+        Self {
+            kind: BuilderKind::Document,
+            children: Vec::new(),
+        }
+    }
+
+    pub fn fragment() -> Self {
+        Self {
+            kind: BuilderKind::DocumentFragment,
+            children: Vec::new(),
+        }
+    }
+
+    /// Returns a document whose `html` element has an empty `head` followed by `body`.
+    pub fn document_with_body(body: NodeBuilder) -> Self {
+        Self::document().child(
+            Self::element("html")
+                .child(Self::element("head"))
+                .child(body),
+        )
+    }
+
+    pub fn doctype(name: &str) -> Self {
+        Self {
+            kind: BuilderKind::Doctype(name.to_string()),
+            children: Vec::new(),
+        }
+    }
+
+    pub fn element(tag: &str) -> Self {
+        Self {
+            kind: BuilderKind::Element {
+                tag: tag.to_string(),
+                attributes: Vec::new(),
+            },
+            children: Vec::new(),
+        }
+    }
+
+    pub fn text(text: &str) -> Self {
+        Self {
+            kind: BuilderKind::Text(text.to_string()),
+            children: Vec::new(),
+        }
+    }
+
+    pub fn comment(data: &str) -> Self {
+        Self {
+            kind: BuilderKind::Comment(data.to_string()),
+            children: Vec::new(),
+        }
+    }
+
+    pub fn processing_instruction(target: &str, data: &str) -> Self {
+        Self {
+            kind: BuilderKind::ProcessingInstruction {
+                target: target.to_string(),
+                data: data.to_string(),
+            },
+            children: Vec::new(),
+        }
+    }
+
+    pub fn attribute(mut self, name: &str, value: &str) -> Self {
+        match self.kind {
+            BuilderKind::Element {
+                ref mut attributes, ..
+            } => {
+                let mut attr = Attribute::new();
+                name.chars().for_each(|c| attr.add_name(c));
+                value.chars().for_each(|c| attr.add_value(c));
+                attributes.push(attr);
+            }
+            _ => panic!("attributes can be added only to an element"),
+        }
+        self
+    }
+
+    pub fn child(mut self, child: NodeBuilder) -> Self {
+        self.children.push(child);
+        self
+    }
+
+    /// Creates the node and all of its descendants, linking parents and siblings
+    /// the same way as the parser does.
+    pub fn build(self) -> RcRefCell<Node> {
+        let kind = match self.kind {
+            BuilderKind::Document => NodeKind::Document,
+            BuilderKind::DocumentFragment => NodeKind::DocumentFragment,
+            BuilderKind::Doctype(name) => NodeKind::Doctype { name },
+            BuilderKind::Element { tag, attributes } => {
+                NodeKind::Element(Element::new(&tag, attributes))
+            }
+            BuilderKind::Text(s) => NodeKind::Text(s),
+            BuilderKind::Comment(s) => NodeKind::Comment(s),
+            BuilderKind::ProcessingInstruction { target, data } => {
+                NodeKind::ProcessingInstruction { target, data }
+            }
+        };
+        let node = Rc::new(RefCell::new(Node::new(kind)));
+
+        let mut previous: Option<RcRefCell<Node>> = None;
+        for child in self.children {
+            let child = child.build();
+            child.borrow_mut().set_parent(Rc::downgrade(&node));
+            match previous {
+                Some(ref p) => {
+                    p.borrow_mut().set_next_sibling(Some(child.clone()));
+                    child.borrow_mut().set_previous_sibling(Rc::downgrade(p));
+                }
+                None => node.borrow_mut().set_first_child(Some(child.clone())),
+            }
+            node.borrow_mut().set_last_child(Rc::downgrade(&child));
+            previous = Some(child);
+        }
+
+        node
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::dom::node::ElementKind;
+    use crate::renderer::html::parser::HtmlParser;
+    use crate::renderer::html::token::HtmlTokenizer;
+
+    fn parse(html: &str) -> RcRefCell<Node> {
+        let t = HtmlTokenizer::new(html.to_string());
+        let window = HtmlParser::new(t).construct_tree();
+        let document = window.borrow().document();
+        document
+    }
+
+    #[test]
+    fn test_build_links() {
+        let document = NodeBuilder::document()
+            .child(
+                NodeBuilder::element("html")
+                    .child(NodeBuilder::element("head"))
+                    .child(NodeBuilder::element("body")),
+            )
+            .build();
+
+        let html = document.borrow().first_child().expect("html should exist");
+        assert!(html.borrow().is_element(ElementKind::Html));
+        assert!(Rc::ptr_eq(
+            &html
+                .borrow()
+                .parent()
+                .upgrade()
+                .expect("parent should exist"),
+            &document
+        ));
+
+        let head = html.borrow().first_child().expect("head should exist");
+        let body = html
+            .borrow()
+            .last_child()
+            .upgrade()
+            .expect("body should exist");
+        assert!(head.borrow().is_element(ElementKind::Head));
+        assert!(body.borrow().is_element(ElementKind::Body));
+        assert!(Rc::ptr_eq(
+            &head
+                .borrow()
+                .next_sibling()
+                .expect("next sibling should exist"),
+            &body
+        ));
+        assert!(Rc::ptr_eq(
+            &body
+                .borrow()
+                .previous_sibling()
+                .upgrade()
+                .expect("previous sibling should exist"),
+            &head
+        ));
+    }
+
+    #[test]
+    fn test_compare_with_parser() {
+        let expected = NodeBuilder::document()
+            .child(
+                NodeBuilder::element("html")
+                    .child(NodeBuilder::element("head"))
+                    .child(
+                        NodeBuilder::element("body").child(
+                            NodeBuilder::element("p").child(
+                                NodeBuilder::element("a")
+                                    .attribute("foo", "bar")
+                                    .child(NodeBuilder::text("text")),
+                            ),
+                        ),
+                    ),
+            )
+            .build();
+
+        let document = parse("<html><head></head><body><p><a foo=bar>text</a></p></body></html>");
+        assert!(Node::tree_eq(&expected, &document));
+    }
+
+    #[test]
+    fn test_compare_with_parser_mismatch() {
+        let expected = NodeBuilder::document()
+            .child(
+                NodeBuilder::element("html")
+                    .child(NodeBuilder::element("head"))
+                    .child(NodeBuilder::element("body").child(NodeBuilder::text("other"))),
+            )
+            .build();
+
+        let document = parse("<html><head></head><body>text</body></html>");
+        assert!(!Node::tree_eq(&expected, &document));
+    }
+}
@@ -1,6 +1,8 @@
+use alloc::format;
 use alloc::string::{String, ToString};
+use core::cmp::Ordering;
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Attribute {
     name: String,
     value: String,
@@ -22,6 +24,10 @@ impl Attribute {
         self.value.push(c);
     }
 
+    pub fn set_value(&mut self, value: &str) {
+        self.value = value.to_string();
+    }
+
     pub fn name(&self) -> String {
         self.name.clone()
     }
@@ -29,4 +35,133 @@ impl Attribute {
     pub fn value(&self) -> String {
         self.value.clone()
     }
+
+    /// Namespace prefix of a `prefix:name` attribute such as `xlink:href`, or None
+    /// if the name has no prefix.
+    pub fn prefix(&self) -> Option<String> {
+        self.split_name().0.map(|p| p.to_string())
+    }
+
+    /// Name without the namespace prefix.
+    pub fn local_name(&self) -> String {
+        self.split_name().1.to_string()
+    }
+
+    fn split_name(&self) -> (Option<&str>, &str) {
+        match self.name.split_once(':') {
+            Some((prefix, local_name)) if !prefix.is_empty() && !local_name.is_empty() => {
+                (Some(prefix), local_name)
+            }
+            _ => (None, &self.name),
+        }
+    }
+
+    pub fn eq_name(&self, other: &str) -> bool {
+        self.name == other
+    }
+
+    /// Serializes the attribute as ` name="value"` with a leading space, escaping
+    /// `"`, `&` and `<` in the value. An attribute without a value is serialized as
+    /// ` name`.
+    pub fn to_html(&self) -> String {
+        if self.value.is_empty() {
+            return format!(" {}", self.name);
+        }
+
+        let mut value = String::new();
+        for c in self.value.chars() {
+            match c {
+                '"' => value.push_str("&quot;"),
+                '&' => value.push_str("&amp;"),
+                '<' => value.push_str("&lt;"),
+                _ => value.push(c),
+            }
+        }
+        format!(" {}=\"{}\"", self.name, value)
+    }
+}
+
+// attributes are ordered by name first, and by value only when names are the same
+// so that the ordering stays consistent with `Eq`.
+impl Ord for Attribute {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.name
+            .cmp(&other.name)
+            .then_with(|| self.value.cmp(&other.value))
+    }
+}
+
+impl PartialOrd for Attribute {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    fn attribute(name: &str, value: &str) -> Attribute {
+        let mut attr = Attribute::new();
+        name.chars().for_each(|c| attr.add_name(c));
+        value.chars().for_each(|c| attr.add_value(c));
+        attr
+    }
+
+    #[test]
+    fn test_eq_name() {
+        let attr = attribute("class", "a");
+        assert!(attr.eq_name("class"));
+        assert!(!attr.eq_name("id"));
+    }
+
+    #[test]
+    fn test_to_html() {
+        assert_eq!(attribute("class", "a b").to_html(), " class=\"a b\"");
+        assert_eq!(
+            attribute("title", "say \"hi\" & <bye>").to_html(),
+            " title=\"say &quot;hi&quot; &amp; &lt;bye>\""
+        );
+        assert_eq!(attribute("disabled", "").to_html(), " disabled");
+    }
+
+    #[test]
+    fn test_sort() {
+        let mut attributes = [
+            attribute("id", "b"),
+            attribute("class", "z"),
+            attribute("href", "c"),
+            attribute("class", "a"),
+        ];
+        attributes.sort();
+
+        let names: Vec<(String, String)> =
+            attributes.iter().map(|a| (a.name(), a.value())).collect();
+        assert_eq!(
+            names,
+            vec![
+                ("class".to_string(), "a".to_string()),
+                ("class".to_string(), "z".to_string()),
+                ("href".to_string(), "c".to_string()),
+                ("id".to_string(), "b".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_prefix() {
+        let attr = attribute("xlink:href", "#a");
+        assert_eq!(attr.prefix(), Some("xlink".to_string()));
+        assert_eq!(attr.local_name(), "href");
+
+        let attr = attribute("href", "#a");
+        assert_eq!(attr.prefix(), None);
+        assert_eq!(attr.local_name(), "href");
+
+        let attr = attribute(":href", "#a");
+        assert_eq!(attr.prefix(), None);
+        assert_eq!(attr.local_name(), ":href");
+    }
 }
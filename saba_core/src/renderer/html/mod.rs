@@ -1,3 +1,4 @@
 pub mod token;
 pub mod attribute;
 pub mod parser;
+pub mod entities;
@@ -1,99 +1,302 @@
-use crate::renderer::dom::node::{Element, ElementKind, Node, NodeKind, Window};
+use crate::renderer::dom::node::{Element, ElementKind, Node, NodeKind, QuirksMode, Window};
 use crate::renderer::html::attribute::Attribute;
-use crate::renderer::html::token::{HtmlToken, HtmlTokenizer};
+use crate::renderer::html::token::{is_whitespace, HtmlToken, HtmlTokenizer, State};
 use alloc::rc::Rc;
-use alloc::string::ToString;
+use alloc::rc::Weak;
+use alloc::string::{String, ToString};
 use alloc::vec::Vec;
 use core::cell::RefCell;
 use core::str::FromStr;
 
 type RcRefCell<T> = Rc<RefCell<T>>;
 
+/// Default limit of the stack of open elements. Start tags nested deeper than this
+/// are ignored so that a malicious page can't grow the tree unboundedly.
+pub const DEFAULT_MAX_DEPTH: usize = 512;
+
+/// Receives parsing events from `HtmlParser::parse_with`.
+pub trait TreeSink {
+    fn start_element(&mut self, tag: &str, attributes: &[Attribute]);
+    fn end_element(&mut self, tag: &str);
+    fn text(&mut self, text: &str);
+    fn comment(&mut self, _text: &str) {}
+}
+
 #[derive(Debug, Clone)]
 pub struct HtmlParser {
     window: RcRefCell<Window>,
     mode: InsertionMode,
     original_insertion_mode: InsertionMode,
     stack_of_open_elements: Vec<RcRefCell<Node>>,
+    // insertion modes to go back to when </template> appears
+    stack_of_template_insertion_modes: Vec<InsertionMode>,
+    // https://html.spec.whatwg.org/multipage/parsing.html#form-element-pointer
+    form_element: Option<RcRefCell<Node>>,
+    // the context element of the fragment case
+    context: Option<ElementKind>,
     t: HtmlTokenizer,
+    max_depth: usize,
+    // true after the whole input is processed
+    finished: bool,
+    // number of tokens `step` may still process, or None to process all
+    step_budget: Option<usize>,
+    // true if `construct_tree` returned because `step_budget` ran out
+    paused: bool,
+    record_source_ranges: bool,
+    // https://html.spec.whatwg.org/multipage/parsing.html#scripting-flag
+    scripting: bool,
+    // (char index, byte offset) of the latest position converted by `byte_offset`
+    source_cursor: (usize, usize),
+    // tag names which are inserted as `ElementKind::Unknown` elements
+    custom_elements: Vec<String>,
+    ignore_whitespace_text: bool,
+    // (parent, its last child, text) of a white space run which isn't inserted yet
+    // because it may turn out to be a whitespace-only text node
+    pending_whitespace: Option<(RcRefCell<Node>, Option<RcRefCell<Node>>, String)>,
+    keep_processing_instructions: bool,
 }
 
 impl HtmlParser {
     pub fn new(t: HtmlTokenizer) -> Self {
+        Self::with_max_depth(t, DEFAULT_MAX_DEPTH)
+    }
+
+    pub fn with_max_depth(t: HtmlTokenizer, max_depth: usize) -> Self {
         Self {
             window: Rc::new(RefCell::new(Window::new())),
             mode: InsertionMode::Initial,
             original_insertion_mode: InsertionMode::Initial,
             stack_of_open_elements: [].to_vec(),
+            stack_of_template_insertion_modes: [].to_vec(),
+            form_element: None,
+            context: None,
             t,
+            max_depth,
+            finished: false,
+            step_budget: None,
+            paused: false,
+            record_source_ranges: false,
+            scripting: false,
+            source_cursor: (0, 0),
+            custom_elements: Vec::new(),
+            ignore_whitespace_text: false,
+            pending_whitespace: None,
+            keep_processing_instructions: false,
+        }
+    }
+
+    /// Registers a tag name which this implementation doesn't know, e.g. `my-widget`.
+    /// Registered tags in body are inserted as `ElementKind::Unknown` elements
+    /// instead of being ignored.
+    pub fn register_custom_element(&mut self, tag: &str) {
+        let tag = tag.to_ascii_lowercase();
+        if !self.custom_elements.contains(&tag) {
+            self.custom_elements.push(tag);
+        }
+    }
+
+    fn is_custom_element(&self, tag: &str) -> bool {
+        self.custom_elements.iter().any(|t| t == tag)
+    }
+
+    /// Creates a parser which constructs a tree from already tokenized `tokens`.
+    pub fn from_tokens(tokens: Vec<HtmlToken>) -> Self {
+        Self::new(HtmlTokenizer::from_tokens(tokens))
+    }
+
+    /// Processes the input event by event without allocating DOM nodes.
+    /// Unlike `construct_tree`, insertion modes are not applied, so implied tags
+    /// are not generated. End tags without a matching start tag are ignored, and
+    /// elements which are not closed end at the end tag of their ancestor or at
+    /// the end of input. Consecutive chars are reported as one text.
+    pub fn parse_with<V: TreeSink>(t: HtmlTokenizer, sink: &mut V) {
+        let mut open_elements: Vec<String> = Vec::new();
+        let mut text = String::new();
+
+        for token in t {
+            if let HtmlToken::Char(c) = token {
+                text.push(c);
+                continue;
+            }
+            if !text.is_empty() {
+                sink.text(&text);
+                text.clear();
+            }
+
+            match token {
+                HtmlToken::StartTag {
+                    ref tag,
+                    self_closing,
+                    ref attributes,
+                } => {
+                    sink.start_element(tag, attributes);
+                    if self_closing {
+                        sink.end_element(tag);
+                    } else {
+                        open_elements.push(tag.to_string());
+                    }
+                }
+                HtmlToken::EndTag { ref tag } => {
+                    if !open_elements.contains(tag) {
+                        // parse error. ignore token.
+                        continue;
+                    }
+                    while let Some(current) = open_elements.pop() {
+                        sink.end_element(&current);
+                        if current == *tag {
+                            break;
+                        }
+                    }
+                }
+                HtmlToken::Comment(ref data) => sink.comment(data),
+                HtmlToken::Doctype { .. } | HtmlToken::Char(_) | HtmlToken::EOF => {}
+            }
+        }
+
+        if !text.is_empty() {
+            sink.text(&text);
+        }
+        while let Some(current) = open_elements.pop() {
+            sink.end_element(&current);
         }
     }
 
+    /// Processes the input and returns the constructed tree. This can be called only
+    /// once after the input is closed because the tokenizer is exhausted then.
     pub fn construct_tree(&mut self) -> Rc<RefCell<Window>> {
-        let mut token = self.t.next();
+        assert!(
+            !self.finished,
+            "construct_tree is called after the whole input is processed"
+        );
+        self.paused = false;
+        let mut token = self.next_token();
 
         while token.is_some() {
             match self.mode {
                 InsertionMode::Initial => {
-                    // this implementation does not support DOCTYPE token
-                    if let Some(HtmlToken::Char(_)) = token {
-                        token = self.t.next();
+                    if let Some(HtmlToken::Doctype { ref name }) = token {
+                        let document = self.window.borrow().document();
+                        let node = Node::new(NodeKind::Doctype { name: name.clone() });
+                        append_child(&document, Rc::new(RefCell::new(node)));
+                        // public and system identifiers aren't tokenized, so only
+                        // the name decides the mode. limited-quirks is never set.
+                        if name != "html" {
+                            self.window.borrow_mut().set_quirks_mode(QuirksMode::Quirks);
+                        }
+                        self.mode = InsertionMode::BeforeHtml;
+                        token = self.next_token();
+                        continue;
+                    }
+                    if token.as_ref().is_some_and(|t| t.is_whitespace()) {
+                        token = self.next_token();
+                        continue;
+                    }
+                    if let Some(HtmlToken::Comment(ref data)) = token {
+                        self.insert_comment(data);
+                        token = self.next_token();
                         continue;
                     }
 
+                    // no doctype
+                    self.window.borrow_mut().set_quirks_mode(QuirksMode::Quirks);
                     self.mode = InsertionMode::BeforeHtml;
                     continue;
                 }
-                InsertionMode::BeforeHtml => match token {
+                InsertionMode::BeforeHtml => {
                     // <html>
-                    Some(HtmlToken::Char(c)) => {
-                        if c == ' ' || c == '\n' {
-                            token = self.t.next();
+                    match token {
+                        Some(HtmlToken::Doctype { .. }) => {
+                            // parse error. ignore token.
+                            token = self.next_token();
                             continue;
                         }
-                    }
-                    Some(HtmlToken::StartTag {
-                        ref tag,
-                        ref attributes,
-                        ..
-                    }) => {
-                        if tag == "html" {
-                            self.insert_element(tag, attributes.to_vec());
-                            self.mode = InsertionMode::BeforeHead;
-                            token = self.t.next();
+                        Some(HtmlToken::Comment(ref data)) => {
+                            self.insert_comment(data);
+                            token = self.next_token();
                             continue;
                         }
+                        Some(ref t) if t.is_whitespace() => {
+                            // ignore token
+                            token = self.next_token();
+                            continue;
+                        }
+                        Some(HtmlToken::Char(_)) => {}
+                        Some(HtmlToken::StartTag {
+                            ref tag,
+                            ref attributes,
+                            ..
+                        }) => {
+                            if tag == "html" {
+                                self.insert_element(tag, attributes.to_vec());
+                                self.mode = InsertionMode::BeforeHead;
+                                token = self.next_token();
+                                continue;
+                            }
+                        }
+                        Some(HtmlToken::EndTag { ref tag }) => {
+                            if tag != "head" && tag != "body" && tag != "html" && tag != "br" {
+                                // parse error. ignore token.
+                                token = self.next_token();
+                                continue;
+                            }
+                        }
+                        Some(HtmlToken::EOF) | None => {
+                            break;
+                        }
                     }
-                    Some(HtmlToken::EOF) | None => {
-                        return self.window.clone();
-                    }
-                    _ => {}
-                },
+                    // create an html element and reprocess the token
+                    self.insert_element("html", Vec::new());
+                    self.mode = InsertionMode::BeforeHead;
+                    continue;
+                }
                 InsertionMode::BeforeHead => {
                     // <head>
                     match token {
-                        Some(HtmlToken::Char(c)) => {
-                            if c == ' ' || c == '\n' {
-                                token = self.t.next();
-                                continue;
-                            }
+                        Some(HtmlToken::Doctype { .. }) => {
+                            // parse error. ignore token.
+                            token = self.next_token();
+                            continue;
+                        }
+                        Some(HtmlToken::Comment(ref data)) => {
+                            self.insert_comment(data);
+                            token = self.next_token();
+                            continue;
                         }
+                        Some(ref t) if t.is_whitespace() => {
+                            // ignore token
+                            token = self.next_token();
+                            continue;
+                        }
+                        Some(HtmlToken::Char(_)) => {}
                         Some(HtmlToken::StartTag {
                             ref tag,
                             ref attributes,
                             ..
                         }) => {
+                            if tag == "html" {
+                                self.merge_html_attributes(attributes);
+                                token = self.next_token();
+                                continue;
+                            }
                             if tag == "head" {
                                 self.insert_element(tag, attributes.to_vec());
                                 self.mode = InsertionMode::InHead;
-                                token = self.t.next();
+                                token = self.next_token();
+                                continue;
+                            }
+                        }
+                        Some(HtmlToken::EndTag { ref tag }) => {
+                            // </head>, </body>, </html> and </br> are handled after
+                            // creating a head element
+                            if tag != "head" && tag != "body" && tag != "html" && tag != "br" {
+                                // parse error. ignore token.
+                                token = self.next_token();
                                 continue;
                             }
                         }
                         Some(HtmlToken::EOF) | None => {
-                            return self.window.clone();
+                            break;
                         }
-                        _ => {}
                     }
                     self.insert_element("head", Vec::new());
                     self.mode = InsertionMode::InHead;
@@ -102,30 +305,72 @@ impl HtmlParser {
                 InsertionMode::InHead => {
                     // </head>, <style>, </style>
                     match token {
+                        Some(HtmlToken::Doctype { .. }) => {
+                            // parse error. ignore token.
+                            token = self.next_token();
+                            continue;
+                        }
+                        Some(HtmlToken::Comment(ref data)) => {
+                            self.insert_comment(data);
+                            token = self.next_token();
+                            continue;
+                        }
                         Some(HtmlToken::Char(c)) => {
-                            if c == ' ' || c == '\n' {
+                            if is_whitespace(c) {
                                 self.insert_char(c);
-                                token = self.t.next();
+                                token = self.next_token();
                                 continue;
                             }
+                            // text content closes <head>. reprocess the token in AfterHead
+                            self.pop_until(ElementKind::Head);
+                            self.mode = InsertionMode::AfterHead;
+                            continue;
                         }
                         Some(HtmlToken::StartTag {
                             ref tag,
                             ref attributes,
                             ..
                         }) => {
-                            if tag == "style" || tag == "script" {
-                                self.insert_element(tag, attributes.to_vec());
-                                self.original_insertion_mode = self.mode;
-                                self.mode = InsertionMode::Text;
-                                token = self.t.next();
+                            if tag == "html" {
+                                self.merge_html_attributes(attributes);
+                                token = self.next_token();
+                                continue;
+                            }
+                            if tag == "style"
+                                || tag == "script"
+                                || tag == "title"
+                                || (tag == "noscript" && self.scripting)
+                            {
+                                if self.insert_element(tag, attributes.to_vec()) {
+                                    self.start_text(tag);
+                                }
+                                token = self.next_token();
+                                continue;
+                            }
+                            if tag == "template" {
+                                self.insert_template(attributes.to_vec());
+                                token = self.next_token();
+                                continue;
+                            }
+                            if tag == "base" {
+                                // void element
+                                if self.insert_element(tag, attributes.to_vec()) {
+                                    self.pop_open_element();
+                                }
+                                token = self.next_token();
                                 continue;
                             }
                             if tag == "body" {
                                 self.pop_until(ElementKind::Head);
                                 self.mode = InsertionMode::AfterHead;
+                                continue;
                             }
-                            if let Ok(_element_kind) = ElementKind::from_str(tag) {
+                            if !matches!(
+                                tag.as_str(),
+                                "html" | "head" | "base" | "link" | "meta" | "title" | "noscript"
+                            ) {
+                                // flow content closes <head>. reprocess the token in
+                                // AfterHead, which inserts <body>
                                 self.pop_until(ElementKind::Head);
                                 self.mode = InsertionMode::AfterHead;
                                 continue;
@@ -134,26 +379,47 @@ impl HtmlParser {
                         Some(HtmlToken::EndTag { ref tag }) => {
                             if tag == "head" {
                                 self.mode = InsertionMode::AfterHead;
-                                token = self.t.next();
+                                token = self.next_token();
+                                self.pop_until(ElementKind::Head);
+                                continue;
+                            }
+                            if tag == "template" {
+                                self.close_template();
+                                token = self.next_token();
+                                continue;
+                            }
+                            if tag == "body" || tag == "html" || tag == "br" {
+                                // reprocess the token in AfterHead
                                 self.pop_until(ElementKind::Head);
+                                self.mode = InsertionMode::AfterHead;
                                 continue;
                             }
                         }
                         Some(HtmlToken::EOF) | None => {
-                            return self.window.clone();
+                            break;
                         }
                     }
-                    // ignore unsupported tag
-                    token = self.t.next();
+                    // parse error or unsupported tag. ignore token.
+                    token = self.next_token();
                     continue;
                 }
                 InsertionMode::AfterHead => {
                     // <body>
                     match token {
+                        Some(HtmlToken::Doctype { .. }) => {
+                            // parse error. ignore token.
+                            token = self.next_token();
+                            continue;
+                        }
+                        Some(HtmlToken::Comment(ref data)) => {
+                            self.insert_comment(data);
+                            token = self.next_token();
+                            continue;
+                        }
                         Some(HtmlToken::Char(c)) => {
-                            if c == ' ' || c == '\n' {
+                            if is_whitespace(c) {
                                 self.insert_char(c);
-                                token = self.t.next();
+                                token = self.next_token();
                                 continue;
                             }
                         }
@@ -162,17 +428,30 @@ impl HtmlParser {
                             ref attributes,
                             ..
                         }) => {
+                            if tag == "html" {
+                                self.merge_html_attributes(attributes);
+                                token = self.next_token();
+                                continue;
+                            }
                             if tag == "body" {
                                 self.insert_element(tag, attributes.to_vec());
-                                token = self.t.next();
+                                token = self.next_token();
                                 self.mode = InsertionMode::InBody;
                                 continue;
                             }
                         }
+                        Some(HtmlToken::EndTag { ref tag }) => {
+                            // </body>, </html> and </br> are handled after creating a body
+                            // element
+                            if tag != "body" && tag != "html" && tag != "br" {
+                                // parse error. ignore token.
+                                token = self.next_token();
+                                continue;
+                            }
+                        }
                         Some(HtmlToken::EOF) | None => {
-                            return self.window.clone();
+                            break;
                         }
-                        _ => {}
                     }
                     self.insert_element("body", Vec::new());
                     self.mode = InsertionMode::InBody;
@@ -180,40 +459,160 @@ impl HtmlParser {
                 }
                 InsertionMode::InBody => {
                     match token {
+                        Some(HtmlToken::Doctype { .. }) => {
+                            // parse error. ignore token.
+                            token = self.next_token();
+                            continue;
+                        }
+                        Some(HtmlToken::Comment(ref data)) => {
+                            self.insert_comment(data);
+                            token = self.next_token();
+                            continue;
+                        }
                         Some(HtmlToken::StartTag {
                             ref tag,
                             ref attributes,
                             ..
                         }) => match tag.as_str() {
+                            "html" => {
+                                self.merge_html_attributes(attributes);
+                                token = self.next_token();
+                                continue;
+                            }
+                            "body" => {
+                                // parse error. add attributes to the body if it's open
+                                let body = self
+                                    .stack_of_open_elements
+                                    .get(1)
+                                    .filter(|n| n.borrow().is_element(ElementKind::Body))
+                                    .cloned();
+                                if let Some(body) = body {
+                                    if !self.contain_in_stack(ElementKind::Template) {
+                                        merge_attributes(&body, attributes);
+                                    }
+                                }
+                                token = self.next_token();
+                                continue;
+                            }
                             "p" => {
                                 self.insert_element(tag, attributes.to_vec());
-                                token = self.t.next();
+                                token = self.next_token();
                                 continue;
                             }
-                            "h1" | "h2" => {
+                            "h1" | "h2" | "div" | "pre" => {
                                 self.insert_element(tag, attributes.to_vec());
-                                token = self.t.next();
+                                token = self.next_token();
                                 continue;
                             }
                             "a" => {
+                                if self.contain_in_stack(ElementKind::A) {
+                                    // parse error. anchors can't be nested, so close
+                                    // the open anchor. this implementation doesn't
+                                    // support the adoption agency algorithm.
+                                    self.pop_until(ElementKind::A);
+                                }
+                                self.insert_element(tag, attributes.to_vec());
+                                token = self.next_token();
+                                continue;
+                            }
+                            "form" => {
+                                let in_template = self.contain_in_stack(ElementKind::Template);
+                                if self.form_element.is_some() && !in_template {
+                                    // parse error. forms can't be nested. ignore token.
+                                    token = self.next_token();
+                                    continue;
+                                }
+                                if self.insert_element(tag, attributes.to_vec()) && !in_template {
+                                    self.form_element = self.stack_of_open_elements.last().cloned();
+                                }
+                                token = self.next_token();
+                                continue;
+                            }
+                            "table" => {
+                                if self.insert_element(tag, attributes.to_vec()) {
+                                    self.mode = InsertionMode::InTable;
+                                }
+                                token = self.next_token();
+                                continue;
+                            }
+                            "select" => {
+                                if self.insert_element(tag, attributes.to_vec()) {
+                                    self.mode = InsertionMode::InSelect;
+                                }
+                                token = self.next_token();
+                                continue;
+                            }
+                            "template" => {
+                                self.insert_template(attributes.to_vec());
+                                token = self.next_token();
+                                continue;
+                            }
+                            "script" | "style" => {
+                                // process using the rules for InHead
+                                if self.insert_element(tag, attributes.to_vec()) {
+                                    self.start_text(tag);
+                                }
+                                token = self.next_token();
+                                continue;
+                            }
+                            "br" | "img" => {
+                                // void elements are popped right away whether or not
+                                // the tag is self-closing.
+                                if self.insert_element(tag, attributes.to_vec()) {
+                                    self.pop_open_element();
+                                }
+                                token = self.next_token();
+                                continue;
+                            }
+                            "noscript" => {
+                                if self.insert_element(tag, attributes.to_vec()) && self.scripting {
+                                    self.start_text(tag);
+                                }
+                                token = self.next_token();
+                                continue;
+                            }
+                            "caption" | "col" | "colgroup" | "tbody" | "td" | "tfoot" | "th"
+                            | "thead" | "tr"
+                                if self.contain_in_table_scope(ElementKind::Caption) =>
+                            {
+                                // a table tag closes the current caption.
+                                // reprocess the token in InTable
+                                self.pop_until(ElementKind::Caption);
+                                self.mode = InsertionMode::InTable;
+                                continue;
+                            }
+                            "tbody" | "tr" | "td" | "th" => {
+                                if !self.contain_in_stack(ElementKind::Table) {
+                                    // parse failed. ignore token.
+                                    token = self.next_token();
+                                    continue;
+                                }
+                                // a new cell or row closes the current cell.
+                                // reprocess the token in InTable
+                                self.close_cell();
+                                self.mode = InsertionMode::InTable;
+                                continue;
+                            }
+                            tag if self.is_custom_element(tag) => {
                                 self.insert_element(tag, attributes.to_vec());
-                                token = self.t.next();
+                                token = self.next_token();
                                 continue;
                             }
                             _ => {
-                                token = self.t.next();
+                                token = self.next_token();
                             }
                         },
                         Some(HtmlToken::EndTag { ref tag }) => {
                             match tag.as_str() {
                                 "body" => {
-                                    self.mode = InsertionMode::AfterBody;
-                                    token = self.t.next();
+                                    token = self.next_token();
                                     if !self.contain_in_stack(ElementKind::Body) {
                                         // parse failed. ignore token.
                                         continue;
                                     }
-                                    self.pop_until(ElementKind::Body);
+                                    // body is kept in the stack so that content after
+                                    // </body> is still inserted into body
+                                    self.mode = InsertionMode::AfterBody;
                                     continue;
                                 }
                                 "html" => {
@@ -221,122 +620,759 @@ impl HtmlParser {
                                         self.mode = InsertionMode::AfterBody;
                                         assert!(self.pop_current_node(ElementKind::Html));
                                     } else {
-                                        token = self.t.next();
+                                        token = self.next_token();
                                     }
                                     continue;
                                 }
+                                "br" => {
+                                    // parse error. </br> is treated as <br> without
+                                    // attributes
+                                    if self.insert_element("br", Vec::new()) {
+                                        self.pop_open_element();
+                                    }
+                                    token = self.next_token();
+                                    continue;
+                                }
                                 "p" => {
-                                    token = self.t.next();
+                                    token = self.next_token();
+                                    if !self.contain_in_stack(ElementKind::P) {
+                                        // parse failed. ignore token.
+                                        continue;
+                                    }
                                     self.pop_until(ElementKind::P);
                                     continue;
                                 }
-                                "h1" | "h2" => {
+                                "h1" | "h2" | "div" | "pre" | "noscript" => {
                                     let kind = ElementKind::from_str(tag)
                                         .expect("Failed to convert string to ElementKind.");
-                                    token = self.t.next();
+                                    token = self.next_token();
+                                    if !self.contain_in_stack(kind.clone()) {
+                                        // parse failed. ignore token.
+                                        continue;
+                                    }
                                     self.pop_until(kind);
                                     continue;
                                 }
                                 "a" => {
-                                    token = self.t.next();
+                                    token = self.next_token();
+                                    if !self.contain_in_stack(ElementKind::A) {
+                                        // parse failed. ignore token.
+                                        continue;
+                                    }
                                     self.pop_until(ElementKind::A);
                                     continue;
                                 }
+                                "template" => {
+                                    self.close_template();
+                                    token = self.next_token();
+                                    continue;
+                                }
+                                "form" => {
+                                    token = self.next_token();
+                                    let open = if self.contain_in_stack(ElementKind::Template) {
+                                        self.contain_in_stack(ElementKind::Form)
+                                    } else {
+                                        // the form element pointer is cleared even if
+                                        // the end tag is ignored
+                                        self.form_element.take().is_some_and(|form| {
+                                            self.stack_of_open_elements
+                                                .iter()
+                                                .any(|n| Rc::ptr_eq(n, &form))
+                                        })
+                                    };
+                                    if !open {
+                                        // parse failed. ignore token.
+                                        continue;
+                                    }
+                                    self.pop_until(ElementKind::Form);
+                                    continue;
+                                }
+                                "caption" => {
+                                    token = self.next_token();
+                                    if !self.contain_in_table_scope(ElementKind::Caption) {
+                                        // parse failed. ignore token.
+                                        continue;
+                                    }
+                                    self.pop_until(ElementKind::Caption);
+                                    self.mode = InsertionMode::InTable;
+                                    continue;
+                                }
+                                "td" | "th" => {
+                                    let kind = ElementKind::from_str(tag)
+                                        .expect("Failed to convert string to ElementKind.");
+                                    token = self.next_token();
+                                    if !self.contain_in_table_scope(kind.clone()) {
+                                        // parse failed. ignore token.
+                                        continue;
+                                    }
+                                    self.pop_until(kind);
+                                    self.mode = InsertionMode::InTable;
+                                    continue;
+                                }
+                                "tbody" | "tr" | "table" => {
+                                    let kind = ElementKind::from_str(tag)
+                                        .expect("Failed to convert string to ElementKind.");
+                                    if !self.contain_in_table_scope(kind.clone()) {
+                                        // parse failed. ignore token.
+                                        token = self.next_token();
+                                        continue;
+                                    }
+                                    // close the current cell and reprocess the token in InTable
+                                    self.close_cell();
+                                    self.mode = InsertionMode::InTable;
+                                    continue;
+                                }
+                                tag if self.is_custom_element(tag) => {
+                                    let kind = ElementKind::Unknown(tag.to_string());
+                                    token = self.next_token();
+                                    if !self.contain_in_stack(kind.clone()) {
+                                        // parse failed. ignore token.
+                                        continue;
+                                    }
+                                    self.pop_until(kind);
+                                    continue;
+                                }
                                 _ => {
-                                    token = self.t.next();
+                                    token = self.next_token();
                                 }
                             }
                         }
                         Some(HtmlToken::Char(c)) => {
                             self.insert_char(c);
-                            token = self.t.next();
+                            token = self.next_token();
                             continue;
                         }
                         Some(HtmlToken::EOF) | None => {
-                            return self.window.clone();
+                            break;
                         }
                     }
                 }
-                InsertionMode::Text => {
+                InsertionMode::InTable => {
+                    // <tbody>, <tr>, <td>, <th> and their end tags.
+                    // this implementation also handles table body and row contexts here
                     match token {
-                        Some(HtmlToken::EOF) | None => {
-                            return self.window.clone();
-                        }
-                        Some(HtmlToken::EndTag { ref tag }) => {
-                            if tag == "style" {
-                                self.pop_until(ElementKind::Style);
-                                self.mode = self.original_insertion_mode;
-                                token = self.t.next();
-                                continue;
-                            }
-                            if tag == "script" {
-                                self.pop_until(ElementKind::Script);
-                                self.mode = self.original_insertion_mode;
-                                token = self.t.next();
-                                continue;
-                            }
+                        Some(HtmlToken::Doctype { .. }) => {
+                            // parse error. ignore token.
+                            token = self.next_token();
+                            continue;
                         }
-                        Some(HtmlToken::Char(c)) => {
-                            self.insert_char(c);
-                            token = self.t.next();
+                        Some(HtmlToken::Comment(ref data)) => {
+                            self.insert_comment(data);
+                            token = self.next_token();
                             continue;
                         }
-                        _ => {}
-                    }
-                    self.mode = self.original_insertion_mode;
-                }
-                InsertionMode::AfterBody => {
-                    match token {
                         Some(HtmlToken::Char(_c)) => {
-                            token = self.t.next();
+                            // texts directly under table are not supported. ignore them
+                            token = self.next_token();
                             continue;
                         }
-                        Some(HtmlToken::EndTag { ref tag }) => {
-                            if tag == "html" {
-                                self.mode = InsertionMode::AfterAfterBody;
-                                token = self.t.next();
+                        Some(HtmlToken::StartTag {
+                            ref tag,
+                            ref attributes,
+                            ..
+                        }) => match tag.as_str() {
+                            "caption" => {
+                                self.clear_stack_back_to(&[ElementKind::Table]);
+                                if self.insert_element(tag, attributes.to_vec()) {
+                                    self.mode = InsertionMode::InCaption;
+                                }
+                                token = self.next_token();
                                 continue;
                             }
-                        }
-                        Some(HtmlToken::EOF) | None => {
-                            return self.window.clone();
-                        }
-                        _ => {}
-                    }
-                    self.mode = InsertionMode::InBody;
-                }
-                InsertionMode::AfterAfterBody => {
-                    match token {
-                        Some(HtmlToken::Char(_c)) => {
-                            token = self.t.next();
-                            continue;
-                        }
-                        Some(HtmlToken::EOF) | None => {
-                            return self.window.clone();
-                        }
-                        _ => {}
-                    }
-                    self.mode = InsertionMode::InBody;
-                }
-            }
-        }
-
-        self.window.clone()
+                            "colgroup" => {
+                                self.clear_stack_back_to(&[ElementKind::Table]);
+                                if self.insert_element(tag, attributes.to_vec()) {
+                                    self.mode = InsertionMode::InColumnGroup;
+                                }
+                                token = self.next_token();
+                                continue;
+                            }
+                            "col" => {
+                                // insert an implicit <colgroup> and reprocess the token
+                                self.clear_stack_back_to(&[ElementKind::Table]);
+                                if !self.insert_element("colgroup", Vec::new()) {
+                                    token = self.next_token();
+                                    continue;
+                                }
+                                self.mode = InsertionMode::InColumnGroup;
+                                continue;
+                            }
+                            "tbody" => {
+                                self.clear_stack_back_to(&[ElementKind::Table]);
+                                self.insert_element(tag, attributes.to_vec());
+                                token = self.next_token();
+                                continue;
+                            }
+                            "tr" => {
+                                self.clear_stack_back_to(&[ElementKind::Tbody, ElementKind::Table]);
+                                if self.adjusted_current_element_is(ElementKind::Table) {
+                                    // implicit <tbody>
+                                    self.insert_element("tbody", Vec::new());
+                                }
+                                self.insert_element(tag, attributes.to_vec());
+                                token = self.next_token();
+                                continue;
+                            }
+                            "td" | "th" => {
+                                self.clear_stack_back_to(&[
+                                    ElementKind::Tr,
+                                    ElementKind::Tbody,
+                                    ElementKind::Table,
+                                ]);
+                                if self.adjusted_current_element_is(ElementKind::Table) {
+                                    // implicit <tbody>
+                                    self.insert_element("tbody", Vec::new());
+                                }
+                                if self.adjusted_current_element_is(ElementKind::Tbody) {
+                                    // implicit <tr>
+                                    self.insert_element("tr", Vec::new());
+                                }
+                                if self.insert_element(tag, attributes.to_vec()) {
+                                    self.mode = InsertionMode::InBody;
+                                }
+                                token = self.next_token();
+                                continue;
+                            }
+                            _ => {
+                                // foster parenting is not supported. ignore token
+                                token = self.next_token();
+                                continue;
+                            }
+                        },
+                        Some(HtmlToken::EndTag { ref tag }) => match tag.as_str() {
+                            "tbody" | "tr" => {
+                                let kind = ElementKind::from_str(tag)
+                                    .expect("Failed to convert string to ElementKind.");
+                                token = self.next_token();
+                                if !self.contain_in_table_scope(kind.clone()) {
+                                    // parse failed. ignore token.
+                                    continue;
+                                }
+                                self.pop_until(kind);
+                                continue;
+                            }
+                            "table" => {
+                                token = self.next_token();
+                                if !self.contain_in_table_scope(ElementKind::Table) {
+                                    // parse failed. ignore token.
+                                    continue;
+                                }
+                                self.pop_until(ElementKind::Table);
+                                self.mode = InsertionMode::InBody;
+                                continue;
+                            }
+                            _ => {
+                                // parse error. ignore token.
+                                token = self.next_token();
+                                continue;
+                            }
+                        },
+                        Some(HtmlToken::EOF) | None => {
+                            break;
+                        }
+                    }
+                }
+                InsertionMode::InCaption => {
+                    // </caption> and table tags which close <caption>
+                    let closes_caption = match token {
+                        Some(HtmlToken::StartTag { ref tag, .. }) => matches!(
+                            tag.as_str(),
+                            "caption"
+                                | "col"
+                                | "colgroup"
+                                | "tbody"
+                                | "td"
+                                | "tfoot"
+                                | "th"
+                                | "thead"
+                                | "tr"
+                        ),
+                        Some(HtmlToken::EndTag { ref tag }) => tag == "caption" || tag == "table",
+                        _ => false,
+                    };
+                    if closes_caption {
+                        let is_end_caption = matches!(token, Some(HtmlToken::EndTag { ref tag }) if tag == "caption");
+                        if !self.contain_in_table_scope(ElementKind::Caption) {
+                            // parse error. ignore token.
+                            token = self.next_token();
+                            continue;
+                        }
+                        self.pop_until(ElementKind::Caption);
+                        self.mode = InsertionMode::InTable;
+                        if is_end_caption {
+                            token = self.next_token();
+                        }
+                        // otherwise reprocess the token in InTable
+                        continue;
+                    }
+                    // caption contents are processed with the rules for InBody
+                    self.mode = InsertionMode::InBody;
+                    continue;
+                }
+                InsertionMode::InColumnGroup => {
+                    // <col> and </colgroup>
+                    match token {
+                        Some(HtmlToken::Doctype { .. }) => {
+                            // parse error. ignore token.
+                            token = self.next_token();
+                            continue;
+                        }
+                        Some(HtmlToken::Comment(ref data)) => {
+                            self.insert_comment(data);
+                            token = self.next_token();
+                            continue;
+                        }
+                        Some(HtmlToken::Char(c)) if is_whitespace(c) => {
+                            self.insert_char(c);
+                            token = self.next_token();
+                            continue;
+                        }
+                        Some(HtmlToken::StartTag {
+                            ref tag,
+                            ref attributes,
+                            ..
+                        }) if tag == "col" => {
+                            // void element
+                            if self.insert_element(tag, attributes.to_vec()) {
+                                self.pop_open_element();
+                            }
+                            token = self.next_token();
+                            continue;
+                        }
+                        Some(HtmlToken::EndTag { ref tag })
+                            if tag == "colgroup" || tag == "col" =>
+                        {
+                            let ignored =
+                                tag == "col" || !self.current_element_is(ElementKind::Colgroup);
+                            token = self.next_token();
+                            if ignored {
+                                // parse error. ignore token.
+                                continue;
+                            }
+                            self.pop_until(ElementKind::Colgroup);
+                            self.mode = InsertionMode::InTable;
+                            continue;
+                        }
+                        Some(HtmlToken::EOF) | None => {
+                            break;
+                        }
+                        _ => {}
+                    }
+                    if !self.current_element_is(ElementKind::Colgroup) {
+                        // parse error. ignore token.
+                        token = self.next_token();
+                        continue;
+                    }
+                    // anything else closes <colgroup>. reprocess the token in InTable
+                    self.pop_until(ElementKind::Colgroup);
+                    self.mode = InsertionMode::InTable;
+                    continue;
+                }
+                InsertionMode::InSelect => {
+                    // <option>, </option>, </select>
+                    match token {
+                        Some(HtmlToken::Doctype { .. }) => {
+                            // parse error. ignore token.
+                            token = self.next_token();
+                            continue;
+                        }
+                        Some(HtmlToken::Comment(ref data)) => {
+                            self.insert_comment(data);
+                            token = self.next_token();
+                            continue;
+                        }
+                        Some(HtmlToken::Char(c)) => {
+                            self.insert_char(c);
+                            token = self.next_token();
+                            continue;
+                        }
+                        Some(HtmlToken::StartTag {
+                            ref tag,
+                            ref attributes,
+                            ..
+                        }) => match tag.as_str() {
+                            "option" => {
+                                // a new option closes the previous one
+                                if self.contain_in_select_scope(ElementKind::Option) {
+                                    self.pop_until(ElementKind::Option);
+                                }
+                                self.insert_element(tag, attributes.to_vec());
+                                token = self.next_token();
+                                continue;
+                            }
+                            "select" => {
+                                // nested select is treated as </select>
+                                if self.contain_in_select_scope(ElementKind::Select) {
+                                    self.pop_until(ElementKind::Select);
+                                    self.mode = self.reset_insertion_mode_appropriately();
+                                }
+                                token = self.next_token();
+                                continue;
+                            }
+                            _ => {
+                                // parse error. ignore token.
+                                token = self.next_token();
+                                continue;
+                            }
+                        },
+                        Some(HtmlToken::EndTag { ref tag }) => match tag.as_str() {
+                            "option" => {
+                                token = self.next_token();
+                                if self.contain_in_select_scope(ElementKind::Option) {
+                                    self.pop_until(ElementKind::Option);
+                                }
+                                continue;
+                            }
+                            "select" => {
+                                token = self.next_token();
+                                if self.contain_in_select_scope(ElementKind::Select) {
+                                    self.pop_until(ElementKind::Select);
+                                    self.mode = self.reset_insertion_mode_appropriately();
+                                }
+                                continue;
+                            }
+                            _ => {
+                                // parse error. ignore token.
+                                token = self.next_token();
+                                continue;
+                            }
+                        },
+                        Some(HtmlToken::EOF) | None => {
+                            break;
+                        }
+                    }
+                }
+                InsertionMode::InTemplate => {
+                    match token {
+                        Some(HtmlToken::Doctype { .. }) => {
+                            // parse error. ignore token.
+                            token = self.next_token();
+                            continue;
+                        }
+                        Some(HtmlToken::Comment(ref data)) => {
+                            self.insert_comment(data);
+                            token = self.next_token();
+                            continue;
+                        }
+                        Some(HtmlToken::EndTag { ref tag }) => {
+                            if tag == "template" {
+                                self.close_template();
+                                token = self.next_token();
+                                continue;
+                            }
+                        }
+                        Some(HtmlToken::EOF) | None => {
+                            break;
+                        }
+                        _ => {}
+                    }
+                    // template contents are processed with the rules for InBody
+                    self.mode = InsertionMode::InBody;
+                    continue;
+                }
+                InsertionMode::Text => {
+                    match token {
+                        Some(HtmlToken::Doctype { .. }) => {
+                            // parse error. ignore token.
+                            token = self.next_token();
+                            continue;
+                        }
+                        Some(HtmlToken::EOF) | None => {
+                            break;
+                        }
+                        Some(HtmlToken::EndTag { ref tag }) => {
+                            let kind = ElementKind::from_str(tag).ok();
+                            if let Some(kind) = kind.filter(|k| self.current_element_is(k.clone()))
+                            {
+                                self.pop_until(kind);
+                                self.mode = self.original_insertion_mode;
+                                token = self.next_token();
+                                continue;
+                            }
+                        }
+                        Some(HtmlToken::Char(c)) => {
+                            self.insert_char(c);
+                            token = self.next_token();
+                            continue;
+                        }
+                        _ => {}
+                    }
+                    // tags in raw text elements such as <style> are text
+                    let source = self.t.source(self.t.token_range());
+                    source.chars().for_each(|c| self.insert_char(c));
+                    token = self.next_token();
+                }
+                InsertionMode::AfterBody => {
+                    match token {
+                        Some(HtmlToken::Doctype { .. }) => {
+                            // parse error. ignore token.
+                            token = self.next_token();
+                            continue;
+                        }
+                        Some(HtmlToken::Comment(ref data)) => {
+                            // inserted into the html element
+                            let html = self.stack_of_open_elements.first().cloned();
+                            let parent = html.unwrap_or_else(|| self.window.borrow().document());
+                            self.insert_comment_into(parent, data);
+                            token = self.next_token();
+                            continue;
+                        }
+                        Some(HtmlToken::Char(c)) => {
+                            if is_whitespace(c) {
+                                // process using the rules for InBody
+                                self.insert_char(c);
+                                token = self.next_token();
+                                continue;
+                            }
+                        }
+                        Some(HtmlToken::EndTag { ref tag }) => {
+                            if tag == "html" {
+                                self.mode = InsertionMode::AfterAfterBody;
+                                token = self.next_token();
+                                continue;
+                            }
+                        }
+                        Some(HtmlToken::EOF) | None => {
+                            break;
+                        }
+                        _ => {}
+                    }
+                    self.mode = InsertionMode::InBody;
+                }
+                InsertionMode::AfterAfterBody => {
+                    match token {
+                        Some(HtmlToken::Doctype { .. }) => {
+                            // parse error. ignore token.
+                            token = self.next_token();
+                            continue;
+                        }
+                        Some(HtmlToken::Comment(ref data)) => {
+                            let document = self.window.borrow().document();
+                            self.insert_comment_into(document, data);
+                            token = self.next_token();
+                            continue;
+                        }
+                        Some(HtmlToken::Char(c)) => {
+                            if is_whitespace(c) {
+                                // process using the rules for InBody
+                                self.insert_char(c);
+                                token = self.next_token();
+                                continue;
+                            }
+                        }
+                        Some(HtmlToken::EOF) | None => {
+                            break;
+                        }
+                        _ => {}
+                    }
+                    self.mode = InsertionMode::InBody;
+                }
+            }
+        }
+
+        self.finished = self.t.is_closed() && !self.paused;
+        if self.record_source_ranges && self.finished {
+            // elements still open end at the end of input
+            let end = self.t.token_range().end;
+            let end = self.byte_offset(end);
+            for node in self.stack_of_open_elements.iter() {
+                set_source_end(node, end);
+            }
+        }
+
+        self.window.clone()
+    }
+
+    /// Processes one token and returns true, or returns false if there is no token
+    /// to process, i.e. the whole input is processed or more input is awaited.
+    pub fn step(&mut self) -> bool {
+        if self.finished {
+            return false;
+        }
+        self.step_budget = Some(1);
+        self.construct_tree();
+        let processed = self.step_budget == Some(0);
+        self.step_budget = None;
+        processed
+    }
+
+    /// The window whose tree is being constructed. The tree can be inspected while
+    /// parsing with `step` or `feed`.
+    pub fn window(&self) -> Rc<RefCell<Window>> {
+        self.window.clone()
+    }
+
+    fn next_token(&mut self) -> Option<HtmlToken> {
+        if self.step_budget == Some(0) {
+            self.paused = true;
+            return None;
+        }
+        let token = self.t.next();
+        if let (Some(_), Some(budget)) = (&token, self.step_budget.as_mut()) {
+            *budget -= 1;
+        }
+        token
+    }
+
+    /// Sets whether scripting is enabled. When it is enabled, the contents of
+    /// `<noscript>` are parsed as raw text. This is disabled by default because this
+    /// browser doesn't run scripts.
+    pub fn set_scripting(&mut self, scripting: bool) {
+        self.scripting = scripting;
+    }
+
+    /// Keeps `<?target data?>` as processing instruction nodes instead of comments,
+    /// e.g. for `<?xml version="1.0"?>` in XML-ish inputs. This is disabled by
+    /// default because they are bogus comments in html.
+    pub fn set_keep_processing_instructions(&mut self, keep: bool) {
+        self.keep_processing_instructions = keep;
+    }
+
+    /// Records the byte range of the source html in each element. This is disabled
+    /// by default to avoid the overhead.
+    pub fn set_record_source_ranges(&mut self, record: bool) {
+        self.record_source_ranges = record;
+    }
+
+    /// Doesn't create text nodes which consist only of white space, e.g. the ones
+    /// between block elements. Text nodes inside `<pre>` are kept as is.
+    /// This is disabled by default to keep the tree faithful to the input.
+    pub fn with_ignore_whitespace_text(mut self, ignore: bool) -> Self {
+        self.ignore_whitespace_text = ignore;
+        self
+    }
+
+    /// Converts a char index of the input to a byte offset.
+    fn byte_offset(&mut self, index: usize) -> usize {
+        if index < self.source_cursor.0 {
+            self.source_cursor = (0, 0);
+        }
+        let (cursor, offset) = self.source_cursor;
+        self.source_cursor = (index, offset + self.t.byte_len(cursor..index));
+        self.source_cursor.1
+    }
+
+    /// Pops the current node. When source ranges are recorded, the element ends
+    /// after the current token if it is the end tag of the element, or before the
+    /// current token otherwise.
+    fn pop_open_element(&mut self) -> Option<RcRefCell<Node>> {
+        let node = self.stack_of_open_elements.pop()?;
+        if self.record_source_ranges {
+            let range = self.t.token_range();
+            let tag = node.borrow().tag_name().unwrap_or_default();
+            let source = self.t.source(range.clone()).to_ascii_lowercase();
+            let is_end_tag = source
+                .strip_prefix("</")
+                .and_then(|s| s.strip_prefix(tag.as_str()))
+                .is_some_and(|s| !s.starts_with(|c: char| c.is_ascii_alphanumeric()));
+            // a void element ends at its own start tag
+            let is_void = node.borrow().element_kind().is_some_and(|k| k.is_void());
+            let end = if is_end_tag || is_void {
+                range.end
+            } else {
+                range.start
+            };
+            let end = self.byte_offset(end);
+            set_source_end(&node, end);
+        }
+        Some(node)
+    }
+
+    /// Tokenizes and processes a chunk of html. Incomplete tokens at the end of the
+    /// chunk are kept until the next `feed` or `finish`.
+    pub fn feed(&mut self, chunk: &str) {
+        self.t.push_str(chunk);
+        self.construct_tree();
+    }
+
+    /// Processes the remaining input and returns the constructed tree.
+    pub fn finish(&mut self) -> Rc<RefCell<Window>> {
+        self.t.close();
+        self.construct_tree()
+    }
+
+    /// https://html.spec.whatwg.org/multipage/parsing.html#parsing-html-fragments
+    /// Parses the input as a fragment in the context of a body element and returns a
+    /// DocumentFragment node whose children are the parsed nodes.
+    pub fn construct_fragment(&mut self) -> Rc<RefCell<Node>> {
+        self.construct_fragment_with_context(ElementKind::Body)
+    }
+
+    /// Parses the input as a fragment in the context of a `context` element, like
+    /// setting `innerHTML` of the element.
+    pub fn construct_fragment_with_context(&mut self, context: ElementKind) -> Rc<RefCell<Node>> {
+        self.insert_element("html", Vec::new());
+        let root = self.stack_of_open_elements[0].clone();
+        if context == ElementKind::Template {
+            self.stack_of_template_insertion_modes
+                .push(InsertionMode::InTemplate);
+        }
+        self.context = Some(context.clone());
+        self.mode = self.reset_insertion_mode_appropriately();
+        let raw_text = match context {
+            ElementKind::Style | ElementKind::Script | ElementKind::Title => true,
+            ElementKind::Noscript => self.scripting,
+            _ => false,
+        };
+        if raw_text {
+            self.start_text(&context.to_string());
+        }
+        self.t.close();
+        self.construct_tree();
+
+        let fragment = Rc::new(RefCell::new(Node::new(NodeKind::DocumentFragment)));
+        let first_child = root.borrow().first_child();
+        let mut child = first_child.clone();
+        while let Some(node) = child {
+            node.borrow_mut().set_parent(Rc::downgrade(&fragment));
+            child = node.borrow().next_sibling();
+        }
+        fragment.borrow_mut().set_first_child(first_child);
+        fragment
+            .borrow_mut()
+            .set_last_child(root.borrow().last_child());
+        root.borrow_mut().set_first_child(None);
+        root.borrow_mut().set_last_child(Weak::new());
+        fragment
     }
 
-    fn create_element(&self, tag: &str, attributes: Vec<Attribute>) -> Node {
-        let elem = Element::new(tag, attributes);
-        Node::new(NodeKind::Element(elem))
+    /// Switches to the Text mode for the contents of `tag`, and the tokenizer to the
+    /// state which doesn't tokenize them as markup.
+    fn start_text(&mut self, tag: &str) {
+        let state = match tag {
+            "title" => State::Rcdata,
+            "script" => State::ScriptData,
+            _ => State::Rawtext,
+        };
+        self.t.switch_to(state);
+        self.original_insertion_mode = self.mode;
+        self.mode = InsertionMode::Text;
     }
 
-    fn insert_element(&mut self, tag: &str, attributes: Vec<Attribute>) {
+    /// Inserts an element and pushes it onto the stack of open elements. Returns
+    /// false without inserting it if the stack already has `max_depth` elements, but
+    /// html, head and body are always inserted to keep the document structure.
+    fn insert_element(&mut self, tag: &str, attributes: Vec<Attribute>) -> bool {
+        if self.stack_of_open_elements.len() >= self.max_depth
+            && !matches!(tag, "html" | "head" | "body")
+        {
+            // too deeply nested. ignore token.
+            return false;
+        }
+        let mut element = Element::new(tag, attributes);
+        if self.record_source_ranges {
+            let start = self.t.token_range().start;
+            let start = self.byte_offset(start);
+            element.set_source_range(start..start);
+        }
+
         let window = self.window.borrow();
         let current = match self.stack_of_open_elements.last() {
-            Some(e) => e.clone(),
+            Some(e) => insertion_parent(e.clone()),
             None => window.document(),
         };
 
-        let node = Rc::new(RefCell::new(self.create_element(tag, attributes)));
+        let node = Rc::new(RefCell::new(Node::new(NodeKind::Element(element))));
 
         if current.borrow().first_child().is_some() {
             let mut last_sibling = current.borrow().first_child();
@@ -369,273 +1405,1760 @@ impl HtmlParser {
         node.borrow_mut().set_parent(Rc::downgrade(&current));
 
         self.stack_of_open_elements.push(node);
+        true
     }
 
-    fn pop_current_node(&mut self, element_kind: ElementKind) -> bool {
-        let current = match self.stack_of_open_elements.last() {
-            Some(e) => e,
-            None => return false,
-        };
-        if current.borrow().element_kind() == Some(element_kind) {
-            // pop if last elem is target kind
-            self.stack_of_open_elements.pop();
-            return true;
+    /// https://html.spec.whatwg.org/multipage/parsing.html#parsing-main-inhead
+    /// Inserts a template element whose children are inserted into its template
+    /// contents instead of the element itself.
+    fn insert_template(&mut self, attributes: Vec<Attribute>) {
+        if !self.insert_element("template", attributes) {
+            return;
         }
-        false
+        if let Some(template) = self.stack_of_open_elements.last() {
+            let content = Rc::new(RefCell::new(Node::new(NodeKind::DocumentFragment)));
+            template.borrow_mut().set_template_content(Some(content));
+        }
+        self.stack_of_template_insertion_modes.push(self.mode);
+        self.mode = InsertionMode::InTemplate;
     }
 
-    fn pop_until(&mut self, element_kind: ElementKind) {
-        assert!(
-            self.contain_in_stack(element_kind),
+    fn close_template(&mut self) {
+        if !self.contain_in_stack(ElementKind::Template) {
+            // parse failed. ignore token.
+            return;
+        }
+        self.pop_until(ElementKind::Template);
+        self.mode = self
+            .stack_of_template_insertion_modes
+            .pop()
+            .unwrap_or(InsertionMode::InBody);
+    }
+
+    /// https://html.spec.whatwg.org/multipage/parsing.html#reset-the-insertion-mode-appropriately
+    /// The context element of the fragment case is used instead of the bottommost
+    /// node of the stack.
+    fn reset_insertion_mode_appropriately(&self) -> InsertionMode {
+        for (i, node) in self.stack_of_open_elements.iter().enumerate().rev() {
+            let last = i == 0;
+            let kind = match (last, self.context.clone()) {
+                (true, Some(kind)) => Some(kind),
+                _ => node.borrow().element_kind(),
+            };
+            match kind {
+                Some(ElementKind::Select) => return InsertionMode::InSelect,
+                Some(ElementKind::Td | ElementKind::Th) if !last => return InsertionMode::InBody,
+                Some(ElementKind::Tr | ElementKind::Tbody | ElementKind::Table) => {
+                    return InsertionMode::InTable
+                }
+                Some(ElementKind::Caption) => return InsertionMode::InCaption,
+                Some(ElementKind::Colgroup) => return InsertionMode::InColumnGroup,
+                Some(ElementKind::Template) => {
+                    return self
+                        .stack_of_template_insertion_modes
+                        .last()
+                        .copied()
+                        .unwrap_or(InsertionMode::InTemplate)
+                }
+                Some(ElementKind::Head) if !last => return InsertionMode::InHead,
+                Some(ElementKind::Body) => return InsertionMode::InBody,
+                // this implementation doesn't keep the head element pointer
+                Some(ElementKind::Html) => return InsertionMode::BeforeHead,
+                _ => {}
+            }
+            if last {
+                return InsertionMode::InBody;
+            }
+        }
+        InsertionMode::InBody
+    }
+
+    /// Returns true if the current node, i.e. the last element in the stack, is
+    /// `element_kind`.
+    fn current_element_is(&self, element_kind: ElementKind) -> bool {
+        self.stack_of_open_elements
+            .last()
+            .is_some_and(|n| n.borrow().is_element(element_kind))
+    }
+
+    /// https://html.spec.whatwg.org/multipage/parsing.html#adjusted-current-node
+    /// Like `current_element_is`, but the context element of the fragment case stands
+    /// in for the root html element.
+    fn adjusted_current_element_is(&self, element_kind: ElementKind) -> bool {
+        match &self.context {
+            Some(context) if self.stack_of_open_elements.len() == 1 => *context == element_kind,
+            _ => self.current_element_is(element_kind),
+        }
+    }
+
+    fn pop_current_node(&mut self, element_kind: ElementKind) -> bool {
+        let current = match self.stack_of_open_elements.last() {
+            Some(e) => e,
+            None => return false,
+        };
+        if current.borrow().is_element(element_kind) {
+            // pop if last elem is target kind
+            self.pop_open_element();
+            return true;
+        }
+        false
+    }
+
+    fn pop_until(&mut self, element_kind: ElementKind) {
+        assert!(
+            self.contain_in_stack(element_kind.clone()),
             "Stack doesn't have an element {:?}",
             element_kind
         );
 
         loop {
-            let current = match self.stack_of_open_elements.pop() {
+            let current = match self.pop_open_element() {
                 Some(e) => e,
                 None => return,
             };
-            if current.borrow().element_kind() == Some(element_kind) {
+            if current.borrow().is_element(element_kind.clone()) {
+                return;
+            }
+        }
+    }
+
+    fn contain_in_stack(&mut self, element_kind: ElementKind) -> bool {
+        self.stack_of_open_elements
+            .iter()
+            .map(|e| e.borrow().is_element(element_kind.clone()))
+            .any(|b| b)
+    }
+
+    /// https://html.spec.whatwg.org/multipage/parsing.html#has-an-element-in-table-scope
+    fn contain_in_table_scope(&self, element_kind: ElementKind) -> bool {
+        for e in self.stack_of_open_elements.iter().rev() {
+            if e.borrow().is_element(element_kind.clone()) {
+                return true;
+            }
+            if e.borrow().is_element(ElementKind::Table) {
+                return false;
+            }
+        }
+        false
+    }
+
+    /// https://html.spec.whatwg.org/multipage/parsing.html#has-an-element-in-select-scope
+    fn contain_in_select_scope(&self, element_kind: ElementKind) -> bool {
+        for e in self.stack_of_open_elements.iter().rev() {
+            if e.borrow().is_element(element_kind.clone()) {
+                return true;
+            }
+            if e.borrow().is_element(ElementKind::Select) {
+                return false;
+            }
+        }
+        false
+    }
+
+    /// Pops elements until the current node is one of `element_kinds`.
+    fn clear_stack_back_to(&mut self, element_kinds: &[ElementKind]) {
+        while let Some(current) = self.stack_of_open_elements.last() {
+            let kind = current.borrow().element_kind();
+            if kind.is_some_and(|k| element_kinds.contains(&k) || k == ElementKind::Html) {
                 return;
             }
+            self.pop_open_element();
+        }
+    }
+
+    /// Adds attributes of a repeated `<html>` start tag to the root html element
+    /// unless the element already has them. This is a parse error.
+    fn merge_html_attributes(&mut self, attributes: &[Attribute]) {
+        if self.contain_in_stack(ElementKind::Template) {
+            // ignore token
+            return;
+        }
+        if let Some(html) = self.stack_of_open_elements.first() {
+            merge_attributes(html, attributes);
+        }
+    }
+
+    /// https://html.spec.whatwg.org/multipage/parsing.html#close-the-cell
+    fn close_cell(&mut self) {
+        if self.contain_in_table_scope(ElementKind::Td) {
+            self.pop_until(ElementKind::Td);
+        } else if self.contain_in_table_scope(ElementKind::Th) {
+            self.pop_until(ElementKind::Th);
+        }
+    }
+
+    fn create_char(&self, c: char) -> Node {
+        let mut s = "".to_string();
+        s.push(c);
+        Node::new(NodeKind::Text(s))
+    }
+
+    /// Inserts a comment into the current node, or into the document if no element
+    /// is open.
+    fn insert_comment(&mut self, data: &str) {
+        let parent = match self.stack_of_open_elements.last() {
+            Some(e) => insertion_parent(e.clone()),
+            None => self.window.borrow().document(),
+        };
+        self.insert_comment_into(parent, data);
+    }
+
+    fn insert_comment_into(&mut self, parent: RcRefCell<Node>, data: &str) {
+        // the tokenizer emits `<?...>` as a bogus comment starting with '?'
+        let kind = match data.strip_prefix('?') {
+            Some(pi) if self.keep_processing_instructions => {
+                let pi = pi.strip_suffix('?').unwrap_or(pi);
+                let (target, data) = pi.split_once(is_whitespace).unwrap_or((pi, ""));
+                NodeKind::ProcessingInstruction {
+                    target: target.to_string(),
+                    data: data.trim_start_matches(is_whitespace).to_string(),
+                }
+            }
+            _ => NodeKind::Comment(data.to_string()),
+        };
+        let node = Rc::new(RefCell::new(Node::new(kind)));
+        append_child(&parent, node);
+    }
+
+    fn insert_char(&mut self, c: char) {
+        let current = match self.stack_of_open_elements.last() {
+            Some(e) => insertion_parent(e.clone()),
+            None => return,
+        };
+        // append to the text node right before the insertion point if any
+        let last_child = current.borrow().last_child_rc();
+        if let Some(last_child) = last_child {
+            if let NodeKind::Text(ref mut s) = last_child.borrow_mut().kind {
+                s.push(c);
+                return;
+            }
+        }
+        let mut node = self.create_char(c);
+        if self.ignore_whitespace_text && !self.contain_in_stack(ElementKind::Pre) {
+            // the pending run is over if anything is inserted after it
+            let last_child = current.borrow().last_child_rc();
+            let pending = self.pending_whitespace.take().filter(|(parent, last, _)| {
+                Rc::ptr_eq(parent, &current)
+                    && match (last, &last_child) {
+                        (Some(a), Some(b)) => Rc::ptr_eq(a, b),
+                        (None, None) => true,
+                        _ => false,
+                    }
+            });
+            let mut text = pending.map(|(_, _, text)| text).unwrap_or_default();
+            text.push(c);
+            if is_whitespace(c) {
+                self.pending_whitespace = Some((current, last_child, text));
+                return;
+            }
+            node = Node::new(NodeKind::Text(text));
+        }
+        append_child(&current, Rc::new(RefCell::new(node)));
+    }
+}
+
+fn set_source_end(node: &RcRefCell<Node>, end: usize) {
+    if let NodeKind::Element(ref mut e) = node.borrow_mut().kind {
+        if let Some(range) = e.source_range() {
+            e.set_source_range(range.start..end);
+        }
+    }
+}
+
+fn append_child(parent: &RcRefCell<Node>, child: RcRefCell<Node>) {
+    let last_child = parent.borrow().last_child_rc();
+    match last_child {
+        Some(last) => {
+            last.borrow_mut().set_next_sibling(Some(child.clone()));
+            child
+                .borrow_mut()
+                .set_previous_sibling(Rc::downgrade(&last));
+        }
+        None => parent.borrow_mut().set_first_child(Some(child.clone())),
+    }
+    parent.borrow_mut().set_last_child(Rc::downgrade(&child));
+    child.borrow_mut().set_parent(Rc::downgrade(parent));
+}
+
+/// Adds each of `attributes` to `node` if it doesn't have the attribute yet.
+fn merge_attributes(node: &RcRefCell<Node>, attributes: &[Attribute]) {
+    if let NodeKind::Element(ref mut e) = node.borrow_mut().kind {
+        for attr in attributes {
+            if e.get_attribute(&attr.name()).is_none() {
+                e.set_attribute(&attr.name(), &attr.value());
+            }
+        }
+    }
+}
+
+/// Children of a template element are inserted into its template contents.
+fn insertion_parent(node: RcRefCell<Node>) -> RcRefCell<Node> {
+    let content = node.borrow().template_content();
+    content.unwrap_or(node)
+}
+
+/// https://html.spec.whatwg.org/multipage/parsing.html#the-insertion-mode
+#[derive(Debug, Clone, Copy)]
+pub enum InsertionMode {
+    Initial,
+    BeforeHtml,
+    BeforeHead,
+    InHead,
+    AfterHead,
+    InBody,
+    InTable,
+    InCaption,
+    InColumnGroup,
+    InSelect,
+    InTemplate,
+    Text,
+    AfterBody,
+    AfterAfterBody,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::dom::testing::NodeBuilder;
+    use alloc::format;
+    use alloc::vec;
+
+    #[test]
+    fn test_empty() {
+        let html = "".to_string();
+        let t = HtmlTokenizer::new(html);
+        let window = HtmlParser::new(t).construct_tree();
+        let expected = Rc::new(RefCell::new(Node::new(NodeKind::Document)));
+
+        assert_eq!(expected, window.borrow().document());
+    }
+
+    #[test]
+    fn test_body() {
+        let html = "<html><head></head><body></body></html>".to_string();
+        let t = HtmlTokenizer::new(html);
+        let window = HtmlParser::new(t).construct_tree();
+        let document = window.borrow().document();
+        assert_eq!(
+            Rc::new(RefCell::new(Node::new(NodeKind::Document))),
+            document
+        );
+
+        let html = document
+            .borrow()
+            .first_child()
+            .expect("failed to get a first child of document");
+        assert_eq!(
+            Rc::new(RefCell::new(Node::new(NodeKind::Element(Element::new(
+                "html",
+                Vec::new()
+            ))))),
+            html
+        );
+
+        let head = html
+            .borrow()
+            .first_child()
+            .expect("failed to get a first child of html");
+        assert_eq!(
+            Rc::new(RefCell::new(Node::new(NodeKind::Element(Element::new(
+                "head",
+                Vec::new()
+            ))))),
+            head
+        );
+
+        let body = head
+            .borrow()
+            .next_sibling()
+            .expect("failed to get a next sibling of head");
+        assert_eq!(
+            Rc::new(RefCell::new(Node::new(NodeKind::Element(Element::new(
+                "body",
+                Vec::new()
+            ))))),
+            body
+        );
+    }
+
+    #[test]
+    fn test_text() {
+        let html = "<html><head></head><body>text</body></html>".to_string();
+        let t = HtmlTokenizer::new(html);
+        let window = HtmlParser::new(t).construct_tree();
+        let document = window.borrow().document();
+        assert_eq!(
+            Rc::new(RefCell::new(Node::new(NodeKind::Document))),
+            document
+        );
+
+        let html = document
+            .borrow()
+            .first_child()
+            .expect("failed to get a first child of document");
+        assert_eq!(
+            Rc::new(RefCell::new(Node::new(NodeKind::Element(Element::new(
+                "html",
+                Vec::new()
+            ))))),
+            html
+        );
+
+        let body = html
+            .borrow()
+            .first_child()
+            .expect("failed to get a first child of document")
+            .borrow()
+            .next_sibling()
+            .expect("failed to get a next sibling of head");
+        assert_eq!(
+            Rc::new(RefCell::new(Node::new(NodeKind::Element(Element::new(
+                "body",
+                Vec::new()
+            ))))),
+            body
+        );
+
+        let text = body
+            .borrow()
+            .first_child()
+            .expect("failed to get a first child of document");
+        assert_eq!(
+            Rc::new(RefCell::new(Node::new(NodeKind::Text("text".to_string())))),
+            text
+        );
+    }
+
+    #[test]
+    fn test_multiple_nodes() {
+        let html = "<html><head></head><body><p><a foo=bar>text</a></p></body></html>".to_string();
+        let t = HtmlTokenizer::new(html);
+        let window = HtmlParser::new(t).construct_tree();
+        let document = window.borrow().document();
+
+        let body = document
+            .borrow()
+            .first_child()
+            .expect("failed to get a first child of document")
+            .borrow()
+            .first_child()
+            .expect("failed to get a first child of document")
+            .borrow()
+            .next_sibling()
+            .expect("failed to get a next sibling of head");
+        assert_eq!(
+            Rc::new(RefCell::new(Node::new(NodeKind::Element(Element::new(
+                "body",
+                Vec::new()
+            ))))),
+            body
+        );
+
+        let p = body
+            .borrow()
+            .first_child()
+            .expect("failed to get a first child of body");
+        assert_eq!(
+            Rc::new(RefCell::new(Node::new(NodeKind::Element(Element::new(
+                "p",
+                Vec::new()
+            ))))),
+            p
+        );
+
+        let mut attr = Attribute::new();
+        "foo".chars().for_each(|c| attr.add_name(c));
+        "bar".chars().for_each(|c| attr.add_value(c));
+
+        let a = p
+            .borrow()
+            .first_child()
+            .expect("failed to get a first child of p");
+        assert_eq!(
+            Rc::new(RefCell::new(Node::new(NodeKind::Element(Element::new(
+                "a",
+                vec![attr]
+            ))))),
+            a
+        );
+
+        let text = a
+            .borrow()
+            .first_child()
+            .expect("failed to get a first child of a");
+        assert_eq!(
+            Rc::new(RefCell::new(Node::new(NodeKind::Text("text".to_string())))),
+            text
+        );
+    }
+
+    #[test]
+    fn test_no_html_tag() {
+        let html = "<body>x</body>".to_string();
+        let t = HtmlTokenizer::new(html);
+        let window = HtmlParser::new(t).construct_tree();
+
+        let expected = NodeBuilder::document_with_body(
+            NodeBuilder::element("body").child(NodeBuilder::text("x")),
+        )
+        .build();
+        assert!(Node::tree_eq(&expected, &window.borrow().document()));
+    }
+
+    #[test]
+    fn test_text_before_html() {
+        let html = "text".to_string();
+        let t = HtmlTokenizer::new(html);
+        let window = HtmlParser::new(t).construct_tree();
+
+        let expected = NodeBuilder::document_with_body(
+            NodeBuilder::element("body").child(NodeBuilder::text("text")),
+        )
+        .build();
+        assert!(Node::tree_eq(&expected, &window.borrow().document()));
+    }
+
+    #[test]
+    fn test_end_tag_in_before_head() {
+        let expected = NodeBuilder::document_with_body(
+            NodeBuilder::element("body").child(NodeBuilder::text("x")),
+        )
+        .build();
+
+        for html in [
+            "<html></head><body>x</body></html>",
+            "<html></p><head></head><body>x</body></html>",
+        ] {
+            let t = HtmlTokenizer::new(html.to_string());
+            let window = HtmlParser::new(t).construct_tree();
+            assert!(Node::tree_eq(&expected, &window.borrow().document()));
+        }
+    }
+
+    #[test]
+    fn test_end_tag_in_after_head() {
+        let expected = NodeBuilder::document_with_body(NodeBuilder::element("body")).build();
+
+        for html in [
+            "<html><head></head></body></html>",
+            "<html><head></head></p><body></body></html>",
+        ] {
+            let t = HtmlTokenizer::new(html.to_string());
+            let window = HtmlParser::new(t).construct_tree();
+            assert!(Node::tree_eq(&expected, &window.borrow().document()));
+        }
+    }
+
+    #[test]
+    fn test_feed() {
+        let html = "<html><head><style>p { color: red; }</style></head><body><h1 id=title>title</h1><p class=\"a&amp;b\">text</p><a href='x&#65;'>link</a></body></html>";
+        let t = HtmlTokenizer::new(html.to_string());
+        let expected = HtmlParser::new(t).construct_tree();
+
+        let mut parser = HtmlParser::new(HtmlTokenizer::new(String::new()));
+        let mut buf = [0u8; 4];
+        for c in html.chars() {
+            parser.feed(c.encode_utf8(&mut buf));
+        }
+        let window = parser.finish();
+
+        assert!(Node::tree_eq(
+            &expected.borrow().document(),
+            &window.borrow().document()
+        ));
+    }
+
+    #[test]
+    fn test_text_switches_tokenizer_state() {
+        let mut parser = HtmlParser::new(HtmlTokenizer::new(String::new()));
+        parser.feed("<html><head><title>");
+        assert_eq!(parser.t.current_state(), State::Rcdata);
+        parser.feed("a &amp; <b>b</b></title><style>");
+        assert_eq!(parser.t.current_state(), State::Rawtext);
+        parser.feed("p > a {}</style><script>");
+        assert_eq!(parser.t.current_state(), State::ScriptData);
+        parser.feed("if (a</b) {}</script>");
+        assert_eq!(parser.t.current_state(), State::Data);
+        let window = parser.finish();
+
+        let expected = NodeBuilder::document()
+            .child(
+                NodeBuilder::element("html").child(
+                    NodeBuilder::element("head")
+                        .child(
+                            NodeBuilder::element("title").child(NodeBuilder::text("a & <b>b</b>")),
+                        )
+                        .child(NodeBuilder::element("style").child(NodeBuilder::text("p > a {}")))
+                        .child(
+                            NodeBuilder::element("script").child(NodeBuilder::text("if (a</b) {}")),
+                        ),
+                ),
+            )
+            .build();
+        assert!(Node::tree_eq(&expected, &window.borrow().document()));
+    }
+
+    #[test]
+    fn test_step() {
+        let t = HtmlTokenizer::new("<p>ab</p>".to_string());
+        let mut parser = HtmlParser::new(t);
+        let window = parser.window();
+        let debug_tree = |parser: &HtmlParser| parser.window().borrow().to_debug_tree();
+
+        // <p> implies html, head and body
+        assert!(parser.step());
+        assert_eq!(
+            debug_tree(&parser),
+            "#document\n  <html>\n    <head>\n    <body>\n      <p>\n"
+        );
+        assert!(parser.step());
+        assert_eq!(
+            debug_tree(&parser),
+            "#document\n  <html>\n    <head>\n    <body>\n      <p>\n        \"a\"\n"
+        );
+        assert!(parser.step());
+        assert!(debug_tree(&parser).ends_with("        \"ab\"\n"));
+        // </p> and EOF
+        assert!(parser.step());
+        assert!(parser.step());
+        assert!(!parser.step());
+        assert!(!parser.step());
+
+        // the window is the same one that is being constructed
+        assert!(Rc::ptr_eq(&window, &parser.window()));
+    }
+
+    #[test]
+    fn test_eof_in_tag() {
+        let expected = NodeBuilder::document_with_body(
+            NodeBuilder::element("body")
+                .child(NodeBuilder::element("p").child(NodeBuilder::text("a"))),
+        )
+        .build();
+        for html in ["<p>a<div", "<p>a<div cl", "<p>a<div class=\"x"] {
+            let t = HtmlTokenizer::new(html.to_string());
+            let window = HtmlParser::new(t).construct_tree();
+            assert!(
+                Node::tree_eq(&expected, &window.borrow().document()),
+                "{}",
+                html
+            );
+        }
+    }
+
+    #[test]
+    fn test_feed_tag_split() {
+        let mut parser = HtmlParser::new(HtmlTokenizer::new(String::new()));
+        parser.feed("<html><body><h");
+        parser.feed("1>x</h");
+        parser.feed("1></body></html>");
+        let window = parser.finish();
+
+        let expected = NodeBuilder::document_with_body(
+            NodeBuilder::element("body")
+                .child(NodeBuilder::element("h1").child(NodeBuilder::text("x"))),
+        )
+        .build();
+        assert!(Node::tree_eq(&expected, &window.borrow().document()));
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let html = "<html><body>".to_string()
+            + &"<p>".repeat(10000)
+            + "text"
+            + &"</p>".repeat(10000)
+            + "</body></html>";
+        let t = HtmlTokenizer::new(html);
+        let window = HtmlParser::with_max_depth(t, 5).construct_tree();
+
+        // html and body are on the stack, so only 3 p elements can be nested
+        let mut depth = 0;
+        let mut node = window.borrow().document_element();
+        while let Some(n) = node {
+            if n.borrow().is_element(ElementKind::P) {
+                depth += 1;
+            }
+            node = n.borrow().last_child_rc();
+        }
+        assert_eq!(depth, 3);
+        // html, body, 3 p elements and the text
+        assert_eq!(window.borrow().max_depth(), 6);
+
+        // the limit holds in the other insertion modes too
+        for unit in [
+            "<table><tr><td>",
+            "<table><caption>",
+            "<table><col>",
+            "<select><option>",
+            "<template>",
+        ] {
+            let t = HtmlTokenizer::new(unit.repeat(100) + "x");
+            let window = HtmlParser::with_max_depth(t, 5).construct_tree();
+            // 5 elements and the text
+            assert!(window.borrow().max_depth() <= 6, "{}", unit);
+        }
+    }
+
+    #[test]
+    fn test_whitespace_after_body() {
+        let html = "<html><head></head><body>text</body> \n\t</html>\n".to_string();
+        let t = HtmlTokenizer::new(html);
+        let window = HtmlParser::new(t).construct_tree();
+
+        let expected = NodeBuilder::document_with_body(
+            NodeBuilder::element("body").child(NodeBuilder::text("text \n\t\n")),
+        )
+        .build();
+        assert!(Node::tree_eq(&expected, &window.borrow().document()));
+    }
+
+    #[test]
+    fn test_text_after_body() {
+        let html = "<html><head></head><body><p>a</p></body>b</html>".to_string();
+        let t = HtmlTokenizer::new(html);
+        let window = HtmlParser::new(t).construct_tree();
+
+        let expected = NodeBuilder::document_with_body(
+            NodeBuilder::element("body")
+                .child(NodeBuilder::element("p").child(NodeBuilder::text("a")))
+                .child(NodeBuilder::text("b")),
+        )
+        .build();
+        assert!(Node::tree_eq(&expected, &window.borrow().document()));
+    }
+
+    #[test]
+    fn test_table() {
+        let html = "<html><head></head><body><table>\n<tr><td>x</td><th>y</th></tr>\n<tr><td><p>z</p></td></tr></table><p>after</p></body></html>".to_string();
+        let t = HtmlTokenizer::new(html);
+        let window = HtmlParser::new(t).construct_tree();
+
+        let expected = NodeBuilder::document_with_body(
+            NodeBuilder::element("body")
+                .child(
+                    NodeBuilder::element("table").child(
+                        NodeBuilder::element("tbody")
+                            .child(
+                                NodeBuilder::element("tr")
+                                    .child(NodeBuilder::element("td").child(NodeBuilder::text("x")))
+                                    .child(
+                                        NodeBuilder::element("th").child(NodeBuilder::text("y")),
+                                    ),
+                            )
+                            .child(
+                                NodeBuilder::element("tr").child(NodeBuilder::element("td").child(
+                                    NodeBuilder::element("p").child(NodeBuilder::text("z")),
+                                )),
+                            ),
+                    ),
+                )
+                .child(NodeBuilder::element("p").child(NodeBuilder::text("after"))),
+        )
+        .build();
+        assert!(Node::tree_eq(&expected, &window.borrow().document()));
+
+        let td = window
+            .borrow()
+            .document()
+            .borrow()
+            .descendants()
+            .find(|n| n.borrow().is_element(ElementKind::Td))
+            .expect("failed to find td");
+        assert_eq!(td.borrow().text_content(), "x");
+    }
+
+    #[test]
+    fn test_colgroup() {
+        let expected = NodeBuilder::document_with_body(
+            NodeBuilder::element("body").child(
+                NodeBuilder::element("table")
+                    .child(
+                        NodeBuilder::element("colgroup")
+                            .child(NodeBuilder::element("col"))
+                            .child(NodeBuilder::element("col")),
+                    )
+                    .child(
+                        NodeBuilder::element("tbody").child(
+                            NodeBuilder::element("tr")
+                                .child(NodeBuilder::element("td").child(NodeBuilder::text("x"))),
+                        ),
+                    ),
+            ),
+        )
+        .build();
+        for html in [
+            "<table><colgroup><col><col></colgroup><tr><td>x</td></tr></table>",
+            "<table><colgroup><col><col></col><tr><td>x</td></tr></table>",
+            "<table><col><col><tr><td>x</td></tr></table>",
+        ] {
+            let t = HtmlTokenizer::new(html.to_string());
+            let window = HtmlParser::new(t).construct_tree();
+            assert!(
+                Node::tree_eq(&expected, &window.borrow().document()),
+                "{}",
+                html
+            );
+        }
+    }
+
+    #[test]
+    fn test_form() {
+        let html = "<form id=a><p>x</p><form id=b></form></form><form id=c></form>";
+        let t = HtmlTokenizer::new(html.to_string());
+        let window = HtmlParser::new(t).construct_tree();
+        let expected = NodeBuilder::document_with_body(
+            NodeBuilder::element("body")
+                .child(
+                    NodeBuilder::element("form")
+                        .attribute("id", "a")
+                        .child(NodeBuilder::element("p").child(NodeBuilder::text("x"))),
+                )
+                .child(NodeBuilder::element("form").attribute("id", "c")),
+        )
+        .build();
+        assert!(Node::tree_eq(&expected, &window.borrow().document()));
+
+        let html = "<form><form></form></form>";
+        let t = HtmlTokenizer::new(html.to_string());
+        let window = HtmlParser::new(t).construct_tree();
+        let forms = window
+            .borrow()
+            .elements_where(|e| e.kind() == ElementKind::Form);
+        assert_eq!(forms.len(), 1);
+    }
+
+    #[test]
+    fn test_caption() {
+        let caption =
+            |content| {
+                NodeBuilder::document_with_body(
+                    NodeBuilder::element("body").child(
+                        NodeBuilder::element("table")
+                            .child(NodeBuilder::element("caption").child(content))
+                            .child(NodeBuilder::element("tbody").child(
+                                NodeBuilder::element("tr").child(
+                                    NodeBuilder::element("td").child(NodeBuilder::text("x")),
+                                ),
+                            )),
+                    ),
+                )
+                .build()
+            };
+
+        let html = "<table><caption>T</caption><tr><td>x</td></tr></table>";
+        let t = HtmlTokenizer::new(html.to_string());
+        let window = HtmlParser::new(t).construct_tree();
+        assert!(Node::tree_eq(
+            &caption(NodeBuilder::text("T")),
+            &window.borrow().document()
+        ));
+
+        // <tr> closes the caption
+        let html = "<table><caption><p>T</p><tr><td>x</td></tr></table>";
+        let t = HtmlTokenizer::new(html.to_string());
+        let window = HtmlParser::new(t).construct_tree();
+        assert!(Node::tree_eq(
+            &caption(NodeBuilder::element("p").child(NodeBuilder::text("T"))),
+            &window.borrow().document()
+        ));
+
+        // <col> after text in the caption also closes it
+        let html = "<table><caption>a<col>b</table>";
+        let t = HtmlTokenizer::new(html.to_string());
+        let window = HtmlParser::new(t).construct_tree();
+        let expected = NodeBuilder::document_with_body(
+            NodeBuilder::element("body").child(
+                NodeBuilder::element("table")
+                    .child(NodeBuilder::element("caption").child(NodeBuilder::text("a")))
+                    .child(NodeBuilder::element("colgroup").child(NodeBuilder::element("col"))),
+            ),
+        )
+        .build();
+        assert!(Node::tree_eq(&expected, &window.borrow().document()));
+    }
+
+    #[test]
+    fn test_implicit_tbody_and_tr() {
+        let expected = NodeBuilder::document_with_body(
+            NodeBuilder::element("body").child(
+                NodeBuilder::element("table").child(
+                    NodeBuilder::element("tbody").child(
+                        NodeBuilder::element("tr")
+                            .child(NodeBuilder::element("td").child(NodeBuilder::text("x"))),
+                    ),
+                ),
+            ),
+        )
+        .build();
+        for html in [
+            "<table><td>x</td></table>",
+            "<table><tr><td>x</td></tr></table>",
+            "<table><tbody><td>x</table>",
+        ] {
+            let t = HtmlTokenizer::new(html.to_string());
+            let window = HtmlParser::new(t).construct_tree();
+            assert!(
+                Node::tree_eq(&expected, &window.borrow().document()),
+                "{}",
+                html
+            );
+        }
+    }
+
+    #[test]
+    fn test_table_without_end_tags() {
+        let html = "<table><tbody><tr><td>a<td>b</table>".to_string();
+        let t = HtmlTokenizer::new(html);
+        let window = HtmlParser::new(t).construct_tree();
+
+        let expected = NodeBuilder::document_with_body(
+            NodeBuilder::element("body").child(
+                NodeBuilder::element("table").child(
+                    NodeBuilder::element("tbody").child(
+                        NodeBuilder::element("tr")
+                            .child(NodeBuilder::element("td").child(NodeBuilder::text("a")))
+                            .child(NodeBuilder::element("td").child(NodeBuilder::text("b"))),
+                    ),
+                ),
+            ),
+        )
+        .build();
+        assert!(Node::tree_eq(&expected, &window.borrow().document()));
+    }
+
+    #[test]
+    fn test_select() {
+        let html = "<html><head></head><body><select name=s>\n<option value=a>A</option><option value=b>B</select><p>after</p></body></html>".to_string();
+        let t = HtmlTokenizer::new(html);
+        let window = HtmlParser::new(t).construct_tree();
+
+        let expected = NodeBuilder::document_with_body(
+            NodeBuilder::element("body")
+                .child(
+                    NodeBuilder::element("select")
+                        .attribute("name", "s")
+                        .child(NodeBuilder::text("\n"))
+                        .child(
+                            NodeBuilder::element("option")
+                                .attribute("value", "a")
+                                .child(NodeBuilder::text("A")),
+                        )
+                        .child(
+                            NodeBuilder::element("option")
+                                .attribute("value", "b")
+                                .child(NodeBuilder::text("B")),
+                        ),
+                )
+                .child(NodeBuilder::element("p").child(NodeBuilder::text("after"))),
+        )
+        .build();
+        assert!(Node::tree_eq(&expected, &window.borrow().document()));
+    }
+
+    #[test]
+    fn test_option_without_end_tag() {
+        let html = "<select><option>A<option>B</select>".to_string();
+        let t = HtmlTokenizer::new(html);
+        let window = HtmlParser::new(t).construct_tree();
+
+        let select = window
+            .borrow()
+            .document()
+            .borrow()
+            .descendants()
+            .find(|n| n.borrow().is_element(ElementKind::Select))
+            .expect("failed to find select");
+        let first = select.borrow().first_child().expect("failed to get option");
+        let second = first.borrow().next_sibling().expect("failed to get option");
+        assert!(first.borrow().is_element(ElementKind::Option));
+        assert!(second.borrow().is_element(ElementKind::Option));
+        assert_eq!(first.borrow().text_content(), "A");
+        assert_eq!(second.borrow().text_content(), "B");
+        assert!(second.borrow().next_sibling().is_none());
+    }
+
+    #[test]
+    fn test_template() {
+        let html = "<html><head><template><style>p {}</style></template></head><body><template id=t><p>hidden</p>text</template><p>shown</p></body></html>".to_string();
+        let t = HtmlTokenizer::new(html);
+        let window = HtmlParser::new(t).construct_tree();
+
+        let expected = NodeBuilder::document()
+            .child(
+                NodeBuilder::element("html")
+                    .child(NodeBuilder::element("head").child(NodeBuilder::element("template")))
+                    .child(
+                        NodeBuilder::element("body")
+                            .child(NodeBuilder::element("template").attribute("id", "t"))
+                            .child(NodeBuilder::element("p").child(NodeBuilder::text("shown"))),
+                    ),
+            )
+            .build();
+        assert!(Node::tree_eq(&expected, &window.borrow().document()));
+
+        let body = window
+            .borrow()
+            .document()
+            .borrow()
+            .descendants()
+            .find(|n| n.borrow().is_element(ElementKind::Body))
+            .expect("failed to find body");
+        let template = body.borrow().first_child().expect("failed to get template");
+        let content = template
+            .borrow()
+            .template_content()
+            .expect("template should have content");
+        let expected_content = NodeBuilder::fragment()
+            .child(NodeBuilder::element("p").child(NodeBuilder::text("hidden")))
+            .child(NodeBuilder::text("text"))
+            .build();
+        assert!(Node::tree_eq(&expected_content, &content));
+    }
+
+    #[test]
+    fn test_fragment() {
+        let html = "<p>a</p><h1>b</h1>".to_string();
+        let t = HtmlTokenizer::new(html);
+        let fragment = HtmlParser::new(t).construct_fragment();
+        assert_eq!(fragment.borrow().kind(), NodeKind::DocumentFragment);
+
+        let expected = NodeBuilder::fragment()
+            .child(NodeBuilder::element("p").child(NodeBuilder::text("a")))
+            .child(NodeBuilder::element("h1").child(NodeBuilder::text("b")))
+            .build();
+        assert!(Node::tree_eq(&expected, &fragment));
+    }
+
+    #[test]
+    fn test_source_range() {
+        let html = "<html><body><p>a<a href=x>b</a></p><p>c</body></html>".to_string();
+        let t = HtmlTokenizer::new(html.clone());
+        let mut parser = HtmlParser::new(t);
+        parser.set_record_source_ranges(true);
+        let window = parser.construct_tree();
+
+        let ranges: Vec<_> = window
+            .borrow()
+            .document()
+            .borrow()
+            .descendants()
+            .filter_map(|n| n.borrow().get_element())
+            .filter(|e| e.kind() == ElementKind::P || e.kind() == ElementKind::A)
+            .map(|e| e.source_range().expect("source range should be recorded"))
+            .collect();
+        assert_eq!(ranges.len(), 3);
+        assert_eq!(&html[ranges[0].clone()], "<p>a<a href=x>b</a></p>");
+        assert_eq!(&html[ranges[1].clone()], "<a href=x>b</a>");
+        // </body> doesn't close the p, so it is open until the end of input
+        assert_eq!(&html[ranges[2].clone()], "<p>c</body></html>");
+    }
+
+    #[test]
+    fn test_source_range_disabled() {
+        let t = HtmlTokenizer::new("<p>a</p>".to_string());
+        let window = HtmlParser::new(t).construct_tree();
+        assert!(window
+            .borrow()
+            .document()
+            .borrow()
+            .descendants()
+            .filter_map(|n| n.borrow().get_element())
+            .all(|e| e.source_range().is_none()));
+    }
+
+    #[test]
+    fn test_br_self_closing() {
+        let expected = NodeBuilder::document_with_body(
+            NodeBuilder::element("body").child(
+                NodeBuilder::element("p")
+                    .child(NodeBuilder::text("a"))
+                    .child(NodeBuilder::element("br"))
+                    .child(NodeBuilder::text("b")),
+            ),
+        )
+        .build();
+        for html in ["<p>a<br>b</p>", "<p>a<br/>b</p>", "<p>a<br />b</p>"] {
+            let t = HtmlTokenizer::new(html.to_string());
+            let window = HtmlParser::new(t).construct_tree();
+            let document = window.borrow().document();
+            assert!(Node::tree_eq(&expected, &document), "{}", html);
+        }
+    }
+
+    #[test]
+    fn test_end_br() {
+        let expected = NodeBuilder::document_with_body(
+            NodeBuilder::element("body").child(
+                NodeBuilder::element("p")
+                    .child(NodeBuilder::text("a"))
+                    .child(NodeBuilder::element("br"))
+                    .child(NodeBuilder::text("b")),
+            ),
+        )
+        .build();
+        for html in ["<p>a</br>b</p>", "<p>a<br>b</p>", "<p>a</br>b"] {
+            let t = HtmlTokenizer::new(html.to_string());
+            let window = HtmlParser::new(t).construct_tree();
+            assert!(
+                Node::tree_eq(&expected, &window.borrow().document()),
+                "{}",
+                html
+            );
+        }
+
+        let t = HtmlTokenizer::new("</br>".to_string());
+        let window = HtmlParser::new(t).construct_tree();
+        let expected = NodeBuilder::document_with_body(
+            NodeBuilder::element("body").child(NodeBuilder::element("br")),
+        )
+        .build();
+        assert!(Node::tree_eq(&expected, &window.borrow().document()));
+    }
+
+    #[test]
+    fn test_img() {
+        let html = "<p><img src=\"x.png\" alt=\"y\">a</p>".to_string();
+        let t = HtmlTokenizer::new(html);
+        let window = HtmlParser::new(t).construct_tree();
+
+        let img = window
+            .borrow()
+            .all_elements()
+            .into_iter()
+            .find(|n| n.borrow().is_element(ElementKind::Img))
+            .expect("img should be inserted");
+        let element = img.borrow().get_element().expect("should be an element");
+        assert_eq!(element.get_attribute("src"), Some("x.png".to_string()));
+        assert_eq!(element.get_attribute("alt"), Some("y".to_string()));
+        // img is void, so the text is its sibling
+        assert!(img.borrow().first_child().is_none());
+        let next = img.borrow().next_sibling().expect("failed to get text");
+        assert_eq!(next.borrow().kind(), NodeKind::Text("a".to_string()));
+    }
+
+    #[test]
+    fn test_br_source_range() {
+        let html = "<p>a<br/>b</p>".to_string();
+        let t = HtmlTokenizer::new(html.clone());
+        let mut parser = HtmlParser::new(t);
+        parser.set_record_source_ranges(true);
+        let window = parser.construct_tree();
+
+        let br = window
+            .borrow()
+            .document()
+            .borrow()
+            .descendants()
+            .filter_map(|n| n.borrow().get_element())
+            .find(|e| e.kind() == ElementKind::Br)
+            .expect("br should be inserted");
+        let range = br.source_range().expect("source range should be recorded");
+        assert_eq!(&html[range], "<br/>");
+    }
+
+    #[derive(Default)]
+    struct EventSink {
+        events: Vec<String>,
+    }
+
+    impl TreeSink for EventSink {
+        fn start_element(&mut self, tag: &str, _attributes: &[Attribute]) {
+            self.events.push(format!("<{}>", tag));
+        }
+
+        fn end_element(&mut self, tag: &str) {
+            self.events.push(format!("</{}>", tag));
+        }
+
+        fn text(&mut self, text: &str) {
+            self.events.push(text.to_string());
         }
     }
 
-    fn contain_in_stack(&mut self, element_kind: ElementKind) -> bool {
-        self.stack_of_open_elements
+    #[test]
+    fn test_parse_with() {
+        let t = HtmlTokenizer::new("<html><body><p></p></body></html>".to_string());
+        let mut sink = EventSink::default();
+        HtmlParser::parse_with(t, &mut sink);
+
+        let start_tags: Vec<_> = sink
+            .events
             .iter()
-            .map(|e| e.borrow().element_kind() == Some(element_kind))
-            .any(|b| b)
+            .filter(|e| !e.starts_with("</"))
+            .collect();
+        assert_eq!(start_tags, ["<html>", "<body>", "<p>"]);
     }
 
-    fn create_char(&self, c: char) -> Node {
-        let mut s = "".to_string();
-        s.push(c);
-        Node::new(NodeKind::Text(s))
+    #[test]
+    fn test_parse_with_unbalanced_tags() {
+        let t = HtmlTokenizer::new("<p>a<a>b</p>c</h1><br/>".to_string());
+        let mut sink = EventSink::default();
+        HtmlParser::parse_with(t, &mut sink);
+
+        assert_eq!(
+            sink.events,
+            ["<p>", "a", "<a>", "b", "</a>", "</p>", "c", "<br>", "</br>"]
+        );
     }
 
-    fn insert_char(&mut self, c: char) {
-        let current = match self.stack_of_open_elements.last() {
-            Some(e) => e.clone(),
-            None => return,
-        };
-        if let NodeKind::Text(ref mut s) = current.borrow_mut().kind {
-            s.push(c);
-            return;
-        }
-        if c == '\n' || c == ' ' {
-            return;
-        }
-        let node = Rc::new(RefCell::new(self.create_char(c)));
-        if current.borrow().first_child().is_some() {
-            current
-                .borrow()
-                .first_child()
-                .unwrap()
-                .borrow_mut()
-                .set_next_sibling(Some(node.clone()));
-            node.borrow_mut().set_previous_sibling(Rc::downgrade(
-                &current
-                    .borrow()
-                    .first_child()
-                    .expect("Failed to get a first child."),
-            ));
-        } else {
-            current.borrow_mut().set_first_child(Some(node.clone()));
-        }
-        current.borrow_mut().set_last_child(Rc::downgrade(&node));
-        node.borrow_mut().set_parent(Rc::downgrade(&current));
-        self.stack_of_open_elements.push(node);
+    #[test]
+    fn test_end_a_without_open_a() {
+        let html = "<html><body><p></a>x</p></body></html>".to_string();
+        let t = HtmlTokenizer::new(html);
+        let window = HtmlParser::new(t).construct_tree();
+
+        let expected = NodeBuilder::document_with_body(
+            NodeBuilder::element("body")
+                .child(NodeBuilder::element("p").child(NodeBuilder::text("x"))),
+        )
+        .build();
+        assert!(Node::tree_eq(&expected, &window.borrow().document()));
     }
-}
 
-/// https://html.spec.whatwg.org/multipage/parsing.html#the-insertion-mode
-#[derive(Debug, Clone, Copy)]
-pub enum InsertionMode {
-    Initial,
-    BeforeHtml,
-    BeforeHead,
-    InHead,
-    AfterHead,
-    InBody,
-    Text,
-    AfterBody,
-    AfterAfterBody,
-}
+    #[test]
+    fn test_nested_a() {
+        let html = "<html><body><a>x<a>y</a></body></html>".to_string();
+        let t = HtmlTokenizer::new(html);
+        let window = HtmlParser::new(t).construct_tree();
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::alloc::string::ToString;
-    use alloc::vec;
+        let expected = NodeBuilder::document_with_body(
+            NodeBuilder::element("body")
+                .child(NodeBuilder::element("a").child(NodeBuilder::text("x")))
+                .child(NodeBuilder::element("a").child(NodeBuilder::text("y"))),
+        )
+        .build();
+        assert!(Node::tree_eq(&expected, &window.borrow().document()));
+    }
 
     #[test]
-    fn test_empty() {
-        let html = "".to_string();
+    fn test_noscript_with_scripting_disabled() {
+        let html = "<html><body><noscript><p>x</p></noscript></body></html>".to_string();
         let t = HtmlTokenizer::new(html);
         let window = HtmlParser::new(t).construct_tree();
-        let expected = Rc::new(RefCell::new(Node::new(NodeKind::Document)));
 
-        assert_eq!(expected, window.borrow().document());
+        let expected = NodeBuilder::document_with_body(
+            NodeBuilder::element("body").child(
+                NodeBuilder::element("noscript")
+                    .child(NodeBuilder::element("p").child(NodeBuilder::text("x"))),
+            ),
+        )
+        .build();
+        assert!(Node::tree_eq(&expected, &window.borrow().document()));
     }
 
     #[test]
-    fn test_body() {
-        let html = "<html><head></head><body></body></html>".to_string();
+    fn test_noscript_with_scripting_enabled() {
+        let html = "<html><body><noscript><p>x</p></noscript></body></html>".to_string();
+        let t = HtmlTokenizer::new(html);
+        let mut parser = HtmlParser::new(t);
+        parser.set_scripting(true);
+        let window = parser.construct_tree();
+
+        let expected = NodeBuilder::document_with_body(
+            NodeBuilder::element("body")
+                .child(NodeBuilder::element("noscript").child(NodeBuilder::text("<p>x</p>"))),
+        )
+        .build();
+        assert!(Node::tree_eq(&expected, &window.borrow().document()));
+    }
+
+    #[test]
+    fn test_doctype() {
+        let html = "<!DOCTYPE html><html></html>".to_string();
         let t = HtmlTokenizer::new(html);
         let window = HtmlParser::new(t).construct_tree();
-        let document = window.borrow().document();
-        assert_eq!(
-            Rc::new(RefCell::new(Node::new(NodeKind::Document))),
-            document
-        );
 
-        let html = document
+        let doctype = window
+            .borrow()
+            .document()
             .borrow()
             .first_child()
             .expect("failed to get a first child of document");
+        assert!(Node::tree_eq(
+            &NodeBuilder::doctype("html").build(),
+            &doctype
+        ));
+        let html = doctype.borrow().next_sibling().expect("failed to get html");
+        assert!(html.borrow().is_element(ElementKind::Html));
         assert_eq!(
-            Rc::new(RefCell::new(Node::new(NodeKind::Element(Element::new(
-                "html",
-                Vec::new()
-            ))))),
-            html
+            window
+                .borrow()
+                .document_element()
+                .map(|e| Rc::ptr_eq(&e, &html)),
+            Some(true)
         );
+    }
 
-        let head = html
-            .borrow()
-            .first_child()
-            .expect("failed to get a first child of html");
-        assert_eq!(
-            Rc::new(RefCell::new(Node::new(NodeKind::Element(Element::new(
-                "head",
-                Vec::new()
-            ))))),
-            head
-        );
+    #[test]
+    fn test_ignore_whitespace_text() {
+        let count_nodes = |ignore: bool| {
+            let t = HtmlTokenizer::new("<body>\n  <p>x</p>\n</body>".to_string());
+            let window = HtmlParser::new(t)
+                .with_ignore_whitespace_text(ignore)
+                .construct_tree();
+            let document = window.borrow().document();
+            let count = document.borrow().descendants().count();
+            count
+        };
+        // html, head, body, "\n  ", p, "x", "\n"
+        assert_eq!(count_nodes(false), 7);
+        // html, head, body, p, "x"
+        assert_eq!(count_nodes(true), 5);
 
-        let body = head
-            .borrow()
-            .next_sibling()
-            .expect("failed to get a next sibling of head");
-        assert_eq!(
-            Rc::new(RefCell::new(Node::new(NodeKind::Element(Element::new(
-                "body",
-                Vec::new()
-            ))))),
-            body
+        // white space in a text with other chars, or inside pre, is kept
+        let expected = NodeBuilder::document_with_body(
+            NodeBuilder::element("body")
+                .child(NodeBuilder::element("pre").child(NodeBuilder::text("\n  a  b\n")))
+                .child(NodeBuilder::element("p").child(NodeBuilder::text("  x")))
+                .child(
+                    NodeBuilder::element("p")
+                        .child(NodeBuilder::element("a").child(NodeBuilder::text("b")))
+                        .child(NodeBuilder::text(" c")),
+                ),
+        )
+        .build();
+        let t = HtmlTokenizer::new(
+            "<pre>\n  a  b\n</pre> <p>  x</p>\n<p><a>b</a> c</p> \n".to_string(),
         );
+        let window = HtmlParser::new(t)
+            .with_ignore_whitespace_text(true)
+            .construct_tree();
+        assert!(Node::tree_eq(&expected, &window.borrow().document()));
     }
 
     #[test]
-    fn test_text() {
-        let html = "<html><head></head><body>text</body></html>".to_string();
+    fn test_repeated_html() {
+        let expected = NodeBuilder::document()
+            .child(
+                NodeBuilder::element("html")
+                    .attribute("lang", "en")
+                    .attribute("dir", "ltr")
+                    .child(NodeBuilder::element("head"))
+                    .child(NodeBuilder::element("body").child(NodeBuilder::text("x"))),
+            )
+            .build();
+        for html in [
+            "<html lang=en><html dir=ltr>x",
+            "<html lang=en><head><html dir=ltr lang=fr></head>x",
+            "<html lang=en><body>x<html dir=ltr></body>",
+            "<html lang=en>x</body></html><html dir=ltr>",
+        ] {
+            let t = HtmlTokenizer::new(html.to_string());
+            let window = HtmlParser::new(t).construct_tree();
+            assert!(
+                Node::tree_eq(&expected, &window.borrow().document()),
+                "{}",
+                html
+            );
+        }
+    }
+
+    #[test]
+    fn test_repeated_body() {
+        let expected = NodeBuilder::document_with_body(
+            NodeBuilder::element("body")
+                .attribute("class", "a")
+                .attribute("id", "b")
+                .child(NodeBuilder::element("p").child(NodeBuilder::text("x"))),
+        )
+        .build();
+        for html in [
+            "<body class=a><body id=b><p>x</p>",
+            "<body class=a><p>x<body id=b class=c></p>",
+            "<body class=a><p>x</p></body><body id=b>",
+        ] {
+            let t = HtmlTokenizer::new(html.to_string());
+            let window = HtmlParser::new(t).construct_tree();
+            assert!(
+                Node::tree_eq(&expected, &window.borrow().document()),
+                "{}",
+                html
+            );
+        }
+    }
+
+    #[test]
+    fn test_custom_element() {
+        let html = "<my-widget name=w><p>a</p></my-widget><my-other></my-other>".to_string();
         let t = HtmlTokenizer::new(html);
-        let window = HtmlParser::new(t).construct_tree();
+        let mut parser = HtmlParser::new(t);
+        parser.register_custom_element("my-widget");
+        let window = parser.construct_tree();
         let document = window.borrow().document();
-        assert_eq!(
-            Rc::new(RefCell::new(Node::new(NodeKind::Document))),
-            document
-        );
 
-        let html = document
-            .borrow()
-            .first_child()
-            .expect("failed to get a first child of document");
-        assert_eq!(
-            Rc::new(RefCell::new(Node::new(NodeKind::Element(Element::new(
-                "html",
-                Vec::new()
-            ))))),
-            html
-        );
+        let expected = NodeBuilder::document_with_body(
+            NodeBuilder::element("body").child(
+                NodeBuilder::element("my-widget")
+                    .attribute("name", "w")
+                    .child(NodeBuilder::element("p").child(NodeBuilder::text("a"))),
+            ),
+        )
+        .build();
+        assert!(Node::tree_eq(&expected, &document));
 
-        let body = html
-            .borrow()
-            .first_child()
-            .expect("failed to get a first child of document")
+        let widgets = window.borrow().get_elements_by_name("w");
+        assert_eq!(widgets.len(), 1);
+        let element = widgets[0]
             .borrow()
-            .next_sibling()
-            .expect("failed to get a next sibling of head");
+            .get_element()
+            .expect("should be an element");
         assert_eq!(
-            Rc::new(RefCell::new(Node::new(NodeKind::Element(Element::new(
-                "body",
-                Vec::new()
-            ))))),
-            body
+            element.kind(),
+            ElementKind::Unknown("my-widget".to_string())
         );
+        assert_eq!(element.kind().to_string(), "my-widget");
+    }
 
-        let text = body
-            .borrow()
-            .first_child()
-            .expect("failed to get a first child of document");
-        assert_eq!(
-            Rc::new(RefCell::new(Node::new(NodeKind::Text("text".to_string())))),
-            text
-        );
+    #[test]
+    fn test_comments_before_body() {
+        let html = "<!--a--><!DOCTYPE html><!--b--><html><!--c--><head><!--d--><title>t</title></head><!--e--><body></body></html>";
+        let t = HtmlTokenizer::new(html.to_string());
+        let window = HtmlParser::new(t).construct_tree();
+
+        let expected = NodeBuilder::document()
+            .child(NodeBuilder::comment("a"))
+            .child(NodeBuilder::doctype("html"))
+            .child(NodeBuilder::comment("b"))
+            .child(
+                NodeBuilder::element("html")
+                    .child(NodeBuilder::comment("c"))
+                    .child(
+                        NodeBuilder::element("head")
+                            .child(NodeBuilder::comment("d"))
+                            .child(NodeBuilder::element("title").child(NodeBuilder::text("t"))),
+                    )
+                    .child(NodeBuilder::comment("e"))
+                    .child(NodeBuilder::element("body")),
+            )
+            .build();
+        assert!(Node::tree_eq(&expected, &window.borrow().document()));
+        // a comment before the doctype doesn't cause quirks mode
+        assert_eq!(window.borrow().quirks_mode(), QuirksMode::NoQuirks);
     }
 
     #[test]
-    fn test_multiple_nodes() {
-        let html = "<html><head></head><body><p><a foo=bar>text</a></p></body></html>".to_string();
-        let t = HtmlTokenizer::new(html);
+    fn test_comments_after_head() {
+        let html = "<body><!--a--><table><!--b--><tr><td><select><!--c--></select></td></tr></table><template><!--d--></template></body><!--e--></html><!--f-->";
+        let t = HtmlTokenizer::new(html.to_string());
         let window = HtmlParser::new(t).construct_tree();
-        let document = window.borrow().document();
 
-        let body = document
-            .borrow()
-            .first_child()
-            .expect("failed to get a first child of document")
-            .borrow()
-            .first_child()
-            .expect("failed to get a first child of document")
+        let expected = NodeBuilder::document()
+            .child(
+                NodeBuilder::element("html")
+                    .child(NodeBuilder::element("head"))
+                    .child(
+                        NodeBuilder::element("body")
+                            .child(NodeBuilder::comment("a"))
+                            .child(
+                                NodeBuilder::element("table")
+                                    .child(NodeBuilder::comment("b"))
+                                    .child(
+                                        NodeBuilder::element("tbody").child(
+                                            NodeBuilder::element("tr").child(
+                                                NodeBuilder::element("td").child(
+                                                    NodeBuilder::element("select")
+                                                        .child(NodeBuilder::comment("c")),
+                                                ),
+                                            ),
+                                        ),
+                                    ),
+                            )
+                            .child(NodeBuilder::element("template")),
+                    )
+                    .child(NodeBuilder::comment("e")),
+            )
+            .child(NodeBuilder::comment("f"))
+            .build();
+        assert!(Node::tree_eq(&expected, &window.borrow().document()));
+        let template = window
             .borrow()
-            .next_sibling()
-            .expect("failed to get a next sibling of head");
+            .all_elements()
+            .into_iter()
+            .find(|n| n.borrow().is_element(ElementKind::Template))
+            .expect("failed to get template");
+        assert_eq!(template.borrow().inner_html(), "<!--d-->");
+    }
+
+    #[test]
+    fn test_processing_instruction() {
+        let html = "<?xml version=\"1.0\"?><?empty?><html></html>";
+        let expected = NodeBuilder::document()
+            .child(NodeBuilder::processing_instruction(
+                "xml",
+                "version=\"1.0\"",
+            ))
+            .child(NodeBuilder::processing_instruction("empty", ""))
+            .child(
+                NodeBuilder::element("html")
+                    .child(NodeBuilder::element("head"))
+                    .child(NodeBuilder::element("body")),
+            )
+            .build();
+        let mut parser = HtmlParser::new(HtmlTokenizer::new(html.to_string()));
+        parser.set_keep_processing_instructions(true);
+        let window = parser.construct_tree();
+        assert!(Node::tree_eq(&expected, &window.borrow().document()));
         assert_eq!(
-            Rc::new(RefCell::new(Node::new(NodeKind::Element(Element::new(
-                "body",
-                Vec::new()
-            ))))),
-            body
+            window.borrow().to_debug_tree().lines().nth(1),
+            Some("  <?xml version=\"1.0\"?>")
         );
-
-        let p = body
-            .borrow()
-            .first_child()
-            .expect("failed to get a first child of body");
+        let document = window.borrow().document();
         assert_eq!(
-            Rc::new(RefCell::new(Node::new(NodeKind::Element(Element::new(
-                "p",
-                Vec::new()
-            ))))),
-            p
+            document.borrow().outer_html(),
+            "<?xml version=\"1.0\"?><?empty?><html><head></head><body></body></html>"
         );
 
-        let mut attr = Attribute::new();
-        "foo".chars().for_each(|c| attr.add_name(c));
-        "bar".chars().for_each(|c| attr.add_value(c));
-
-        let a = p
+        // bogus comments by default
+        let window = HtmlParser::new(HtmlTokenizer::new(html.to_string())).construct_tree();
+        let first = window
+            .borrow()
+            .document()
             .borrow()
             .first_child()
-            .expect("failed to get a first child of p");
+            .expect("failed to get a first child of document");
+        let comment = NodeBuilder::comment("?xml version=\"1.0\"?").build();
+        assert!(Node::tree_eq(&comment, &first));
+    }
+
+    #[test]
+    fn test_quirks_mode() {
+        let quirks_mode = |html: &str| {
+            let t = HtmlTokenizer::new(html.to_string());
+            let window = HtmlParser::new(t).construct_tree();
+            let mode = window.borrow().quirks_mode();
+            mode
+        };
+        assert_eq!(quirks_mode("<html></html>"), QuirksMode::Quirks);
         assert_eq!(
-            Rc::new(RefCell::new(Node::new(NodeKind::Element(Element::new(
-                "a",
-                vec![attr]
-            ))))),
-            a
+            quirks_mode("<!DOCTYPE html><html></html>"),
+            QuirksMode::NoQuirks
         );
-
-        let text = a
-            .borrow()
-            .first_child()
-            .expect("failed to get a first child of a");
+        assert_eq!(quirks_mode("<!doctype HTML><p>a</p>"), QuirksMode::NoQuirks);
         assert_eq!(
-            Rc::new(RefCell::new(Node::new(NodeKind::Text("text".to_string())))),
-            text
+            quirks_mode("<!DOCTYPE foo><html></html>"),
+            QuirksMode::Quirks
         );
     }
+
+    #[test]
+    fn test_fragment_in_style_context() {
+        let t = HtmlTokenizer::new("<p>a</p>".to_string());
+        let fragment = HtmlParser::new(t).construct_fragment_with_context(ElementKind::Style);
+
+        let expected = NodeBuilder::fragment()
+            .child(NodeBuilder::text("<p>a</p>"))
+            .build();
+        assert!(Node::tree_eq(&expected, &fragment));
+    }
+
+    #[test]
+    fn test_fragment_in_select_context() {
+        // the context element isn't in the stack, so select tags are ignored
+        for (html, expected) in [
+            (
+                "<option>a</option></select>b",
+                NodeBuilder::fragment()
+                    .child(NodeBuilder::element("option").child(NodeBuilder::text("a")))
+                    .child(NodeBuilder::text("b")),
+            ),
+            (
+                "<option>a<select>b",
+                NodeBuilder::fragment()
+                    .child(NodeBuilder::element("option").child(NodeBuilder::text("ab"))),
+            ),
+        ] {
+            let t = HtmlTokenizer::new(html.to_string());
+            let fragment = HtmlParser::new(t).construct_fragment_with_context(ElementKind::Select);
+            assert!(Node::tree_eq(&expected.build(), &fragment), "{}", html);
+        }
+    }
+
+    #[test]
+    fn test_fragment_in_table_contexts() {
+        for (context, html, expected) in [
+            (
+                ElementKind::Td,
+                "<p>a</p></td>",
+                NodeBuilder::fragment()
+                    .child(NodeBuilder::element("p").child(NodeBuilder::text("a"))),
+            ),
+            (
+                ElementKind::Tr,
+                "<td>a</td><th>b</tr>",
+                NodeBuilder::fragment()
+                    .child(NodeBuilder::element("td").child(NodeBuilder::text("a")))
+                    .child(NodeBuilder::element("th").child(NodeBuilder::text("b"))),
+            ),
+            (
+                ElementKind::Table,
+                "<tr><td>a</td></tr></table>",
+                NodeBuilder::fragment().child(
+                    NodeBuilder::element("tbody").child(
+                        NodeBuilder::element("tr")
+                            .child(NodeBuilder::element("td").child(NodeBuilder::text("a"))),
+                    ),
+                ),
+            ),
+            (
+                ElementKind::Table,
+                "<caption>c</caption><col></table>",
+                NodeBuilder::fragment()
+                    .child(NodeBuilder::element("caption").child(NodeBuilder::text("c")))
+                    .child(NodeBuilder::element("colgroup").child(NodeBuilder::element("col"))),
+            ),
+            (
+                ElementKind::Caption,
+                "<p>a</p></caption>",
+                NodeBuilder::fragment()
+                    .child(NodeBuilder::element("p").child(NodeBuilder::text("a"))),
+            ),
+            (
+                ElementKind::Colgroup,
+                "<col><col></colgroup>",
+                NodeBuilder::fragment()
+                    .child(NodeBuilder::element("col"))
+                    .child(NodeBuilder::element("col")),
+            ),
+            (
+                ElementKind::Select,
+                "<option>a</option><option>b</select>",
+                NodeBuilder::fragment()
+                    .child(NodeBuilder::element("option").child(NodeBuilder::text("a")))
+                    .child(NodeBuilder::element("option").child(NodeBuilder::text("b"))),
+            ),
+            (
+                ElementKind::Template,
+                "<p>a</p></template>",
+                NodeBuilder::fragment()
+                    .child(NodeBuilder::element("p").child(NodeBuilder::text("a"))),
+            ),
+        ] {
+            let t = HtmlTokenizer::new(html.to_string());
+            let fragment = HtmlParser::new(t).construct_fragment_with_context(context);
+            assert!(Node::tree_eq(&expected.build(), &fragment), "{}", html);
+        }
+    }
+
+    #[test]
+    fn test_fragment_in_body_context() {
+        let t = HtmlTokenizer::new("<p>a</p>".to_string());
+        let fragment = HtmlParser::new(t).construct_fragment_with_context(ElementKind::Body);
+
+        let expected = NodeBuilder::fragment()
+            .child(NodeBuilder::element("p").child(NodeBuilder::text("a")))
+            .build();
+        assert!(Node::tree_eq(&expected, &fragment));
+    }
+
+    #[test]
+    fn test_from_tokens() {
+        let start_tag = |tag: &str| HtmlToken::StartTag {
+            tag: tag.to_string(),
+            self_closing: false,
+            attributes: Vec::new(),
+        };
+        let end_tag = |tag: &str| HtmlToken::EndTag {
+            tag: tag.to_string(),
+        };
+        let tokens = vec![
+            start_tag("body"),
+            start_tag("p"),
+            HtmlToken::Char('x'),
+            end_tag("p"),
+            end_tag("body"),
+            HtmlToken::EOF,
+        ];
+        let window = HtmlParser::from_tokens(tokens).construct_tree();
+
+        let expected = NodeBuilder::document_with_body(
+            NodeBuilder::element("body")
+                .child(NodeBuilder::element("p").child(NodeBuilder::text("x"))),
+        )
+        .build();
+        assert!(Node::tree_eq(&expected, &window.borrow().document()));
+
+        // the same tree as the one from the tokenizer
+        let t = HtmlTokenizer::new("<body><p>x</p></body>".to_string());
+        let tokenized = HtmlParser::new(t).construct_tree();
+        assert!(Node::tree_eq(
+            &tokenized.borrow().document(),
+            &window.borrow().document()
+        ));
+    }
+
+    #[test]
+    fn test_text_and_br_in_head() {
+        let expected = NodeBuilder::document_with_body(
+            NodeBuilder::element("body")
+                .child(NodeBuilder::text("text"))
+                .child(NodeBuilder::element("br"))
+                .child(NodeBuilder::text("x")),
+        )
+        .build();
+        for html in ["<html><head>text<br>x</head></html>", "<head>text<br>x"] {
+            let t = HtmlTokenizer::new(html.to_string());
+            let window = HtmlParser::new(t).construct_tree();
+            assert!(
+                Node::tree_eq(&expected, &window.borrow().document()),
+                "{}",
+                html
+            );
+        }
+    }
+
+    #[test]
+    fn test_script_in_body() {
+        let html = "<html><head></head><body><script>let a = 1;</script><p>x</p></body></html>";
+        let t = HtmlTokenizer::new(html.to_string());
+        let window = HtmlParser::new(t).construct_tree();
+        let expected = NodeBuilder::document_with_body(
+            NodeBuilder::element("body")
+                .child(NodeBuilder::element("script").child(NodeBuilder::text("let a = 1;")))
+                .child(NodeBuilder::element("p").child(NodeBuilder::text("x"))),
+        )
+        .build();
+        assert!(Node::tree_eq(&expected, &window.borrow().document()));
+    }
+
+    #[test]
+    fn test_content_after_head_without_body() {
+        let expected = NodeBuilder::document_with_body(
+            NodeBuilder::element("body")
+                .child(NodeBuilder::element("p").child(NodeBuilder::text("x"))),
+        )
+        .build();
+
+        for html in [
+            "<head></head><p>x",
+            "<html><head><p>x",
+            "<html><head><meta><span></span><p>x</p></head></html>",
+        ] {
+            let t = HtmlTokenizer::new(html.to_string());
+            let window = HtmlParser::new(t).construct_tree();
+            assert!(
+                Node::tree_eq(&expected, &window.borrow().document()),
+                "{}",
+                html
+            );
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "construct_tree is called after the whole input is processed")]
+    fn test_construct_tree_twice() {
+        let t = HtmlTokenizer::new("<p>x</p>".to_string());
+        let mut parser = HtmlParser::new(t);
+        parser.construct_tree();
+        parser.construct_tree();
+    }
+
+    #[test]
+    #[should_panic(expected = "construct_tree is called after the whole input is processed")]
+    fn test_feed_after_finish() {
+        let mut parser = HtmlParser::new(HtmlTokenizer::new(String::new()));
+        parser.feed("<p>x");
+        parser.finish();
+        parser.feed("</p>");
+    }
 }
@@ -1,45 +1,239 @@
+use crate::error::Error;
 use crate::renderer::html::attribute::Attribute;
+use crate::renderer::html::entities;
+use alloc::collections::VecDeque;
+use alloc::format;
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
+use core::ops::Range;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct HtmlTokenizer {
     state: State,
+    // index of the next char to consume. the current char is `input[pos - 1]`, and
+    // `pos == input.len() + 1` after the end of file is consumed
     pos: usize,
     reconsume: bool, // only update state and reuse current char
+    // number of consecutive reconsumes of the current char
+    reconsume_count: usize,
+    // `ScriptData`, `Rcdata` or `Rawtext` to go back to from the states for end tags
+    // in text
+    text_state: State,
+    // tag name of the latest start tag, to find the appropriate end tag in text
+    last_start_tag: Option<String>,
     latest_token: Option<HtmlToken>,
     input: Vec<char>,
     buf: String,
+    // false while more input may be appended by `push_str`
+    input_closed: bool,
+    max_attributes: usize,
+    // true while attributes over `max_attributes` are being dropped
+    drop_attribute: bool,
+    errors: Vec<Error>,
+    // tokens to return before tokenizing the input, such as decoded chars of a
+    // character reference
+    pending_tokens: VecDeque<HtmlToken>,
+    // index in `input` where the latest token starts
+    token_start: usize,
+    // counted only when enabled by `set_collect_stats`
+    stats: Option<TokenStats>,
+    // true to keep the case of tag names, e.g. for foreign content
+    preserve_tag_case: bool,
 }
 
+/// Default limit of attributes per tag. Attributes over this limit are dropped.
+pub const DEFAULT_MAX_ATTRIBUTES: usize = 400;
+
+/// Default limit of input size in bytes accepted by `try_new`.
+pub const MAX_INPUT_SIZE: usize = 16 * 1024 * 1024;
+
 impl HtmlTokenizer {
     pub fn new(html: String) -> Self {
+        Self::with_max_attributes(html, DEFAULT_MAX_ATTRIBUTES)
+    }
+
+    /// Same as `new`, but returns an error instead of allocating the input if
+    /// `html` is larger than `MAX_INPUT_SIZE` bytes.
+    pub fn try_new(html: String) -> Result<Self, Error> {
+        Self::try_with_max_input_size(html, MAX_INPUT_SIZE)
+    }
+
+    /// Same as `try_new` with a custom limit of input size in bytes.
+    pub fn try_with_max_input_size(html: String, max_input_size: usize) -> Result<Self, Error> {
+        if html.len() > max_input_size {
+            return Err(Error::UnexpectedInput(format!(
+                "Input is too large: {} bytes (max {} bytes)",
+                html.len(),
+                max_input_size
+            )));
+        }
+        Ok(Self::new(html))
+    }
+
+    pub fn with_max_attributes(html: String, max_attributes: usize) -> Self {
         Self {
             state: State::Data,
             pos: 0,
             reconsume: false,
+            reconsume_count: 0,
+            text_state: State::Data,
+            last_start_tag: None,
             latest_token: None,
             input: html.chars().collect(),
             buf: String::new(),
+            input_closed: true,
+            max_attributes,
+            drop_attribute: false,
+            errors: Vec::new(),
+            pending_tokens: VecDeque::new(),
+            token_start: 0,
+            stats: None,
+            preserve_tag_case: false,
+        }
+    }
+
+    /// Creates a tokenizer which returns `tokens` as they are, e.g. to reuse tokens
+    /// of another tokenizer. The source of the tokens is not available.
+    pub fn from_tokens(tokens: Vec<HtmlToken>) -> Self {
+        let mut t = Self::new(String::new());
+        t.pending_tokens = tokens.into();
+        t
+    }
+
+    /// Consumes all input and returns the tokens. The last token is always `EOF`.
+    pub fn tokenize_all(&mut self) -> Vec<HtmlToken> {
+        let mut tokens: Vec<HtmlToken> = self.by_ref().collect();
+        if tokens.last() != Some(&HtmlToken::EOF) {
+            tokens.push(HtmlToken::EOF);
+        }
+        tokens
+    }
+
+    /// Starts or stops counting tokens by kind.
+    pub fn set_collect_stats(&mut self, collect: bool) {
+        self.stats = if collect {
+            Some(TokenStats::default())
+        } else {
+            None
+        };
+    }
+
+    /// Keeps the case of tag names instead of lowercasing them, which is needed for
+    /// case-sensitive foreign content such as SVG. Attribute names are still
+    /// lowercased.
+    pub fn preserve_tag_case(&mut self, preserve: bool) {
+        self.preserve_tag_case = preserve;
+    }
+
+    /// Numbers of tokens returned so far, or None if stats are not collected.
+    pub fn stats(&self) -> Option<TokenStats> {
+        self.stats
+    }
+
+    /// Parse errors found so far.
+    pub fn errors(&self) -> Vec<Error> {
+        self.errors.clone()
+    }
+
+    /// Appends a chunk of html. Tokens which are not complete yet are kept until
+    /// the rest of the input arrives or `close` is called.
+    pub fn push_str(&mut self, chunk: &str) {
+        self.input.extend(chunk.chars());
+        self.input_closed = false;
+    }
+
+    /// Marks that no more input will be appended.
+    pub fn close(&mut self) {
+        self.input_closed = true;
+    }
+
+    /// Char indices of the latest token in the input.
+    pub fn token_range(&self) -> Range<usize> {
+        self.token_start..self.pos.min(self.input.len())
+    }
+
+    /// Returns the input in the range of char indices.
+    pub fn source(&self, range: Range<usize>) -> String {
+        self.input[range].iter().collect()
+    }
+
+    /// Returns the length in bytes of the input in the range of char indices.
+    pub fn byte_len(&self, range: Range<usize>) -> usize {
+        self.input[range].iter().map(|c| c.len_utf8()).sum()
+    }
+
+    /// Returns whether the input from the current char starts with `s`, or None if
+    /// more input is needed to decide it.
+    fn next_chars_match(&self, s: &str, ignore_case: bool) -> Option<bool> {
+        let rest = &self.input[self.pos - 1..];
+        for (i, expected) in s.chars().enumerate() {
+            let c = match rest.get(i) {
+                Some(c) => c,
+                None if self.input_closed => return Some(false),
+                None => return None,
+            };
+            let matched = match ignore_case {
+                true => c.eq_ignore_ascii_case(&expected),
+                false => *c == expected,
+            };
+            if !matched {
+                return Some(false);
+            }
+        }
+        Some(true)
+    }
+
+    /// Returns true if no more input will be appended.
+    pub fn is_closed(&self) -> bool {
+        self.input_closed
+    }
+
+    /// The state of the state machine, mainly for debugging.
+    pub fn current_state(&self) -> State {
+        self.state.clone()
+    }
+
+    /// Switches the state. The parser calls this after inserting an element whose
+    /// contents are text, e.g. `State::Rcdata` for `<title>`, `State::Rawtext` for
+    /// `<style>` and `State::ScriptData` for `<script>`. The text ends at the end tag
+    /// of the latest start tag.
+    /// https://html.spec.whatwg.org/multipage/parsing.html#parsing-elements-that-contain-only-text
+    pub fn switch_to(&mut self, state: State) {
+        if matches!(state, State::ScriptData | State::Rcdata | State::Rawtext) {
+            self.text_state = state.clone();
         }
+        self.state = state;
     }
 
+    /// https://html.spec.whatwg.org/multipage/parsing.html#appropriate-end-tag-token
+    fn is_appropriate_end_tag(&self) -> bool {
+        match (&self.latest_token, &self.last_start_tag) {
+            (Some(HtmlToken::EndTag { tag }), Some(start_tag)) => tag == start_tag,
+            _ => false,
+        }
+    }
+
+    /// Returns true iff the current char is the end of file, i.e. the last consume
+    /// went past the final char of the closed input.
     fn is_eof(&self) -> bool {
         self.pos > self.input.len()
     }
 
     fn reconsume_input(&mut self) -> char {
         self.reconsume = false;
-        self.input[self.pos - 1]
+        self.input.get(self.pos - 1).copied().unwrap_or(EOF_CHAR)
     }
 
+    /// Consumes the next char. `EOF_CHAR` is returned for the end of file, and
+    /// `is_eof` is true after that.
     fn consume_next_input(&mut self) -> char {
-        let c = self.input[self.pos];
+        let c = self.input.get(self.pos).copied().unwrap_or(EOF_CHAR);
         self.pos += 1;
         c
     }
 
     fn create_tag(&mut self, start_tag_token: bool) {
+        self.drop_attribute = false;
         if start_tag_token {
             // set latest_token
             self.latest_token = Some(HtmlToken::StartTag {
@@ -64,12 +258,20 @@ impl HtmlTokenizer {
         self.latest_token = None;
         assert!(self.latest_token.is_none());
 
+        if let Some(HtmlToken::StartTag { ref tag, .. }) = t {
+            self.last_start_tag = Some(tag.clone());
+        }
         t
     }
 
     fn append_tag_name(&mut self, c: char) {
         assert!(self.latest_token.is_some());
 
+        let c = match self.preserve_tag_case {
+            true => c,
+            false => c.to_ascii_lowercase(),
+        };
+
         if let Some(t) = self.latest_token.as_mut() {
             match t {
                 HtmlToken::StartTag { ref mut tag, .. } | HtmlToken::EndTag { ref mut tag } => {
@@ -87,11 +289,33 @@ impl HtmlTokenizer {
         if let Some(t) = self.latest_token.as_mut() {
             match t {
                 HtmlToken::StartTag {
-                    ref mut attributes, ..
+                    ref tag,
+                    ref mut attributes,
+                    ..
                 } => {
+                    if attributes.len() >= self.max_attributes {
+                        if !self.drop_attribute {
+                            self.errors.push(Error::UnexpectedInput(format!(
+                                "Too many attributes in <{}>. Attributes over {} are dropped.",
+                                tag, self.max_attributes
+                            )));
+                        }
+                        self.drop_attribute = true;
+                        return;
+                    }
                     attributes.push(Attribute::new());
                 }
-                _ => panic!("`latest_token` should be StartTag"),
+                HtmlToken::EndTag { ref tag } => {
+                    // end-tag-with-attributes parse error. the attributes are dropped.
+                    if !self.drop_attribute {
+                        self.errors.push(Error::UnexpectedInput(format!(
+                            "Attributes in an end tag </{}> are dropped.",
+                            tag
+                        )));
+                    }
+                    self.drop_attribute = true;
+                }
+                _ => panic!("`latest_token` should be either StartTag or EndTag"),
             }
         }
     }
@@ -99,6 +323,10 @@ impl HtmlTokenizer {
     fn append_attribute(&mut self, c: char, is_name: bool) {
         assert!(self.latest_token.is_some());
 
+        if self.drop_attribute {
+            return;
+        }
+
         if let Some(t) = self.latest_token.as_mut() {
             match t {
                 HtmlToken::StartTag {
@@ -117,6 +345,97 @@ impl HtmlTokenizer {
         }
     }
 
+    /// https://html.spec.whatwg.org/multipage/parsing.html#character-reference-state
+    /// Consumes a character reference right after `&` and returns the decoded chars.
+    /// Nothing is consumed when the following chars are not a valid reference, so
+    /// the caller should treat `&` as a normal char in that case.
+    fn consume_character_reference(&mut self, in_attribute: bool) -> Option<String> {
+        if self.input.get(self.pos) == Some(&'#') {
+            return self.consume_numeric_character_reference().map(String::from);
+        }
+
+        let (name, value) = entities::lookup_longest(&self.input[self.pos..])?;
+        let end = self.pos + name.len();
+        if in_attribute && !name.ends_with(';') {
+            // for historical reasons, `&amp=` and `&ampx` in attribute values are
+            // not decoded
+            if let Some(c) = self.input.get(end) {
+                if *c == '=' || c.is_ascii_alphanumeric() {
+                    return None;
+                }
+            }
+        }
+        self.pos = end;
+        Some(value.to_string())
+    }
+
+    /// Returns true when a character reference right after `&` may continue in input
+    /// which is not appended yet.
+    fn is_incomplete_character_reference(&self) -> bool {
+        let rest = &self.input[self.pos..];
+        !self.input_closed
+            && rest.len() < entities::MAX_NAME_LEN
+            && rest.iter().all(|c| c.is_ascii_alphanumeric() || *c == '#')
+    }
+
+    /// Decodes a character reference right after `&` and appends it to the attribute
+    /// value. Returns false without consuming `&` when more input is needed.
+    fn append_character_reference_to_attribute(&mut self) -> bool {
+        if self.is_incomplete_character_reference() {
+            self.pos -= 1;
+            return false;
+        }
+        match self.consume_character_reference(true) {
+            Some(s) => s.chars().for_each(|c| self.append_attribute(c, false)),
+            None => self.append_attribute('&', false),
+        }
+        true
+    }
+
+    /// https://html.spec.whatwg.org/multipage/parsing.html#numeric-character-reference-state
+    fn consume_numeric_character_reference(&mut self) -> Option<char> {
+        // skip '#'
+        let mut pos = self.pos + 1;
+        let radix = match self.input.get(pos) {
+            Some('x') | Some('X') => {
+                pos += 1;
+                16
+            }
+            _ => 10,
+        };
+
+        let start = pos;
+        let mut code: u32 = 0;
+        while let Some(d) = self.input.get(pos).and_then(|c| c.to_digit(radix)) {
+            code = code.saturating_mul(radix).saturating_add(d);
+            pos += 1;
+        }
+        if pos == start {
+            // no digits
+            return None;
+        }
+        if self.input.get(pos) == Some(&';') {
+            pos += 1;
+        }
+        self.pos = pos;
+
+        if code == 0 {
+            return Some(char::REPLACEMENT_CHARACTER);
+        }
+        // surrogates and out of range code points are also replaced
+        Some(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER))
+    }
+
+    /// eof-in-tag parse error. The incomplete tag is discarded and EOF is returned.
+    fn eof_in_tag(&mut self) -> Option<HtmlToken> {
+        self.errors.push(Error::UnexpectedInput(
+            "Unexpected end of file in a tag".to_string(),
+        ));
+        self.latest_token = None;
+        self.drop_attribute = false;
+        Some(HtmlToken::EOF)
+    }
+
     fn set_self_closing_flag(&mut self) {
         assert!(self.latest_token.is_some());
 
@@ -126,12 +445,32 @@ impl HtmlTokenizer {
                     ref mut self_closing,
                     ..
                 } => *self_closing = true,
-                _ => panic!("`latest_token` should be StartTag"),
+                HtmlToken::EndTag { ref tag } => {
+                    // end-tag-with-trailing-solidus parse error. ignore the flag.
+                    self.errors.push(Error::UnexpectedInput(format!(
+                        "Self-closing end tag </{}/> is treated as </{}>.",
+                        tag, tag
+                    )));
+                }
+                _ => panic!("`latest_token` should be either StartTag or EndTag"),
             }
         }
     }
 }
 
+/// Placeholder for the end of file passed to the states. States must check
+/// `is_eof` rather than compare chars with this.
+const EOF_CHAR: char = '\0';
+
+// a char is reconsumed only a few times in a row by valid state transitions. more
+// reconsumes than this means a state transition loops without consuming input.
+const MAX_CONSECUTIVE_RECONSUMES: usize = 16;
+
+/// https://infra.spec.whatwg.org/#ascii-whitespace
+pub fn is_whitespace(c: char) -> bool {
+    matches!(c, '\t' | '\n' | '\x0C' | '\r' | ' ')
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum HtmlToken {
     // <foo>
@@ -144,21 +483,84 @@ pub enum HtmlToken {
     EndTag {
         tag: String,
     },
+    // <!DOCTYPE foo>
+    Doctype {
+        name: String,
+    },
+    // <!-- foo -->
+    Comment(String),
     // char
     Char(char),
     // End of file
     EOF,
 }
 
+/// Numbers of tokens by kind.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TokenStats {
+    pub start_tags: usize,
+    pub end_tags: usize,
+    pub chars: usize,
+    pub comments: usize,
+}
+
+impl HtmlToken {
+    /// Returns true if this is a char token of ASCII whitespace.
+    pub fn is_whitespace(&self) -> bool {
+        matches!(self, HtmlToken::Char(c) if is_whitespace(*c))
+    }
+}
+
 impl Iterator for HtmlTokenizer {
     type Item = HtmlToken;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.pos >= self.input.len() {
-            return None;
+        let token = self.next_token();
+        if let (Some(stats), Some(t)) = (self.stats.as_mut(), token.as_ref()) {
+            match t {
+                HtmlToken::StartTag { .. } => stats.start_tags += 1,
+                HtmlToken::EndTag { .. } => stats.end_tags += 1,
+                HtmlToken::Char(_) => stats.chars += 1,
+                HtmlToken::Comment(_) => stats.comments += 1,
+                HtmlToken::Doctype { .. } | HtmlToken::EOF => {}
+            }
+        }
+        token
+    }
+}
+
+impl HtmlTokenizer {
+    fn next_token(&mut self) -> Option<HtmlToken> {
+        if let Some(t) = self.pending_tokens.pop_front() {
+            return Some(t);
         }
+        // returning a token is progress even if the current char is reconsumed
+        self.reconsume_count = 0;
 
         loop {
+            if !self.reconsume && self.pos >= self.input.len() {
+                if !self.input_closed {
+                    // wait for more input. the current state and token are kept so
+                    // that tokenizing can be resumed after `push_str`.
+                    return None;
+                }
+                if self.is_eof() {
+                    // the end of file is already consumed
+                    return None;
+                }
+                // go on to consume the end of file
+            }
+
+            if self.reconsume {
+                self.reconsume_count += 1;
+                debug_assert!(
+                    self.reconsume_count <= MAX_CONSECUTIVE_RECONSUMES,
+                    "too many consecutive reconsumes in {:?} state",
+                    self.state
+                );
+            } else {
+                self.reconsume_count = 0;
+            }
             let c = match self.reconsume {
                 true => self.reconsume_input(),
                 false => self.consume_next_input(),
@@ -166,10 +568,25 @@ impl Iterator for HtmlTokenizer {
 
             match self.state {
                 State::Data => {
+                    self.token_start = self.pos - 1;
                     if c == '<' {
                         self.state = State::TagOpen;
                         continue;
                     }
+                    if c == '&' {
+                        if self.is_incomplete_character_reference() {
+                            // wait for more input
+                            self.pos -= 1;
+                            return None;
+                        }
+                        let mut chars = match self.consume_character_reference(false) {
+                            Some(s) => s.chars().map(HtmlToken::Char).collect::<VecDeque<_>>(),
+                            None => return Some(HtmlToken::Char('&')),
+                        };
+                        let first = chars.pop_front();
+                        self.pending_tokens = chars;
+                        return first;
+                    }
                     if self.is_eof() {
                         return Some(HtmlToken::EOF);
                     }
@@ -181,6 +598,17 @@ impl Iterator for HtmlTokenizer {
                         self.state = State::EndTagOpen;
                         continue;
                     }
+                    if c == '!' {
+                        self.state = State::MarkupDeclarationOpen;
+                        continue;
+                    }
+                    if c == '?' {
+                        // unexpected-question-mark-instead-of-tag-name parse error
+                        self.latest_token = Some(HtmlToken::Comment(String::new()));
+                        self.reconsume = true;
+                        self.state = State::BogusComment;
+                        continue;
+                    }
                     if c.is_ascii_alphabetic() {
                         // tag name starts
                         self.reconsume = true;
@@ -207,7 +635,7 @@ impl Iterator for HtmlTokenizer {
                     }
                 }
                 State::TagName => {
-                    if c == ' ' {
+                    if is_whitespace(c) {
                         // tag name ends
                         self.state = State::BeforeAttributeName;
                         continue;
@@ -222,14 +650,10 @@ impl Iterator for HtmlTokenizer {
                         self.state = State::Data;
                         return self.take_latest_token();
                     }
-                    if c.is_ascii_uppercase() {
-                        // in tag name chars
-                        self.append_tag_name(c.to_ascii_lowercase());
-                        continue;
-                    }
                     if self.is_eof() {
-                        return Some(HtmlToken::EOF);
+                        return self.eof_in_tag();
                     }
+                    // tag names are lowercased in append_tag_name
                     self.append_tag_name(c);
                 }
                 State::BeforeAttributeName => {
@@ -244,9 +668,9 @@ impl Iterator for HtmlTokenizer {
                     self.start_new_attribute();
                 }
                 State::AttributeName => {
-                    if c == ' ' || c == '/' || self.is_eof() {
+                    if is_whitespace(c) || c == '/' || c == '>' || self.is_eof() {
                         // attribute name ends
-                        self.reconsume = false;
+                        self.reconsume = true;
                         self.state = State::AfterAttributeName;
                         continue;
                     }
@@ -255,11 +679,12 @@ impl Iterator for HtmlTokenizer {
                         self.state = State::BeforeAttributeValue;
                         continue;
                     }
-                    // is_name is true
+                    // is_name is true. names are case-insensitive but values are not,
+                    // so only names are lowercased
                     self.append_attribute(c.to_ascii_lowercase(), true);
                 }
                 State::AfterAttributeName => {
-                    if c == ' ' {
+                    if is_whitespace(c) {
                         // ignore white space
                         continue;
                     }
@@ -279,7 +704,7 @@ impl Iterator for HtmlTokenizer {
                         return self.take_latest_token();
                     }
                     if self.is_eof() {
-                        return Some(HtmlToken::EOF);
+                        return self.eof_in_tag();
                     }
                     // next attribute starts
                     self.reconsume = true;
@@ -287,7 +712,7 @@ impl Iterator for HtmlTokenizer {
                     self.start_new_attribute();
                 }
                 State::BeforeAttributeValue => {
-                    if c == ' ' {
+                    if is_whitespace(c) {
                         // ignore white space
                         continue;
                     }
@@ -299,6 +724,11 @@ impl Iterator for HtmlTokenizer {
                         self.state = State::AttributeValueSingleQuoted;
                         continue;
                     }
+                    if c == '>' {
+                        // missing attribute value. the value stays empty
+                        self.state = State::Data;
+                        return self.take_latest_token();
+                    }
                     self.reconsume = true;
                     self.state = State::AttributeValueUnquoted;
                 }
@@ -308,8 +738,14 @@ impl Iterator for HtmlTokenizer {
                         self.state = State::AfterAttributeValueQuoted;
                         continue;
                     }
+                    if c == '&' {
+                        if !self.append_character_reference_to_attribute() {
+                            return None;
+                        }
+                        continue;
+                    }
                     if self.is_eof() {
-                        return Some(HtmlToken::EOF);
+                        return self.eof_in_tag();
                     }
                     // is_name false(value)
                     self.append_attribute(c, false);
@@ -320,14 +756,20 @@ impl Iterator for HtmlTokenizer {
                         self.state = State::AfterAttributeValueQuoted;
                         continue;
                     }
+                    if c == '&' {
+                        if !self.append_character_reference_to_attribute() {
+                            return None;
+                        }
+                        continue;
+                    }
                     if self.is_eof() {
-                        return Some(HtmlToken::EOF);
+                        return self.eof_in_tag();
                     }
                     // is_name false (value)
                     self.append_attribute(c, false);
                 }
                 State::AttributeValueUnquoted => {
-                    if c == ' ' {
+                    if is_whitespace(c) {
                         // attribute value ends
                         self.state = State::BeforeAttributeName;
                         continue;
@@ -337,13 +779,20 @@ impl Iterator for HtmlTokenizer {
                         self.state = State::Data;
                         return self.take_latest_token();
                     }
+                    if c == '&' {
+                        if !self.append_character_reference_to_attribute() {
+                            return None;
+                        }
+                        continue;
+                    }
                     if self.is_eof() {
-                        return Some(HtmlToken::EOF);
+                        return self.eof_in_tag();
                     }
+                    // other chars including `=`, `"`, `'` and `<` are a part of the value
                     self.append_attribute(c, false);
                 }
                 State::AfterAttributeValueQuoted => {
-                    if c == ' ' {
+                    if is_whitespace(c) {
                         // attribute value ends
                         self.state = State::BeforeAttributeName;
                         continue;
@@ -358,11 +807,15 @@ impl Iterator for HtmlTokenizer {
                         return self.take_latest_token();
                     }
                     if self.is_eof() {
-                        return Some(HtmlToken::EOF);
+                        return self.eof_in_tag();
                     }
+                    // missing-whitespace-between-attributes parse error.
                     // next attribute starts
+                    self.errors.push(Error::UnexpectedInput(
+                        "Missing whitespace between attributes".to_string(),
+                    ));
                     self.reconsume = true;
-                    self.state = State::BeforeAttributeValue;
+                    self.state = State::BeforeAttributeName;
                 }
                 State::SelfClosingStartTag => {
                     if c == '>' {
@@ -371,15 +824,174 @@ impl Iterator for HtmlTokenizer {
                         return self.take_latest_token();
                     }
                     if self.is_eof() {
-                        // invalid parse error
+                        return self.eof_in_tag();
+                    }
+                }
+                State::MarkupDeclarationOpen => {
+                    let comment = self.next_chars_match("--", false);
+                    let doctype = self.next_chars_match("doctype", true);
+                    let cdata = self.next_chars_match("[CDATA[", false);
+                    if comment.is_none() || doctype.is_none() || cdata.is_none() {
+                        // wait for more input
+                        self.pos -= 1;
+                        return None;
+                    }
+                    if comment == Some(true) {
+                        self.pos += "--".len() - 1;
+                        self.latest_token = Some(HtmlToken::Comment(String::new()));
+                        self.state = State::Comment;
+                        continue;
+                    }
+                    if doctype == Some(true) {
+                        self.pos += "doctype".len() - 1;
+                        self.latest_token = Some(HtmlToken::Doctype {
+                            name: String::new(),
+                        });
+                        self.state = State::Doctype;
+                        continue;
+                    }
+                    if cdata == Some(true) {
+                        // CDATA sections are allowed only in foreign content, but
+                        // this implementation treats them as text anywhere
+                        self.pos += "[CDATA[".len() - 1;
+                        self.state = State::CdataSection;
+                        continue;
+                    }
+                    // incorrectly-opened-comment parse error
+                    self.latest_token = Some(HtmlToken::Comment(String::new()));
+                    self.reconsume = true;
+                    self.state = State::BogusComment;
+                }
+                State::Doctype => {
+                    if c == '>' {
+                        self.state = State::Data;
+                        return self.take_latest_token();
+                    }
+                    if c.is_ascii_whitespace() {
+                        // ignore white space before the name
+                        continue;
+                    }
+                    self.reconsume = true;
+                    self.state = State::DoctypeName;
+                }
+                State::DoctypeName => {
+                    if c == '>' {
+                        self.state = State::Data;
+                        return self.take_latest_token();
+                    }
+                    if c.is_ascii_whitespace() {
+                        self.state = State::AfterDoctypeName;
+                        continue;
+                    }
+                    if let Some(HtmlToken::Doctype { ref mut name }) = self.latest_token {
+                        name.push(c.to_ascii_lowercase());
+                    }
+                }
+                State::AfterDoctypeName => {
+                    // public and system identifiers are ignored
+                    if c == '>' {
+                        self.state = State::Data;
+                        return self.take_latest_token();
+                    }
+                }
+                State::Comment => {
+                    // this state also covers the comment start and end states
+                    if c == '-' {
+                        match self.next_chars_match("-->", false) {
+                            Some(true) => {
+                                self.pos += "-->".len() - 1;
+                                self.state = State::Data;
+                                return self.take_latest_token();
+                            }
+                            Some(false) => {}
+                            None => {
+                                // wait for more input
+                                self.pos -= 1;
+                                return None;
+                            }
+                        }
+                    }
+                    if self.is_eof() {
+                        // eof-in-comment parse error. emit the comment, and then EOF
+                        self.reconsume = true;
+                        self.state = State::Data;
+                        return self.take_latest_token();
+                    }
+                    if let Some(HtmlToken::Comment(ref mut data)) = self.latest_token {
+                        if c == '>' && (data.is_empty() || data == "-") {
+                            // abrupt-closing-of-empty-comment parse error, e.g. `<!-->`
+                            data.clear();
+                            self.state = State::Data;
+                            return self.take_latest_token();
+                        }
+                        data.push(c);
+                    }
+                }
+                State::BogusComment => {
+                    if c == '>' {
+                        self.state = State::Data;
+                        return self.take_latest_token();
+                    }
+                    if self.is_eof() {
+                        // emit the comment, and then EOF in the data state
+                        self.reconsume = true;
+                        self.state = State::Data;
+                        return self.take_latest_token();
+                    }
+                    if let Some(HtmlToken::Comment(ref mut data)) = self.latest_token {
+                        data.push(c);
+                    }
+                }
+                State::CdataSection => {
+                    if c == ']' {
+                        match self.next_chars_match("]]>", false) {
+                            Some(true) => {
+                                self.pos += "]]>".len() - 1;
+                                self.state = State::Data;
+                                continue;
+                            }
+                            Some(false) => {}
+                            None => {
+                                // wait for more input
+                                self.pos -= 1;
+                                return None;
+                            }
+                        }
+                    }
+                    return Some(HtmlToken::Char(c));
+                }
+                State::ScriptData | State::Rawtext => {
+                    self.token_start = self.pos - 1;
+                    if c == '<' {
+                        self.state = State::ScriptDataLessThanSign;
+                        continue;
+                    }
+                    if self.is_eof() {
                         return Some(HtmlToken::EOF);
                     }
+                    return Some(HtmlToken::Char(c));
                 }
-                State::ScriptData => {
+                State::Rcdata => {
+                    // same as ScriptData except that character references are decoded
+                    self.token_start = self.pos - 1;
                     if c == '<' {
                         self.state = State::ScriptDataLessThanSign;
                         continue;
                     }
+                    if c == '&' {
+                        if self.is_incomplete_character_reference() {
+                            // wait for more input
+                            self.pos -= 1;
+                            return None;
+                        }
+                        let mut chars = match self.consume_character_reference(false) {
+                            Some(s) => s.chars().map(HtmlToken::Char).collect::<VecDeque<_>>(),
+                            None => return Some(HtmlToken::Char('&')),
+                        };
+                        let first = chars.pop_front();
+                        self.pending_tokens = chars;
+                        return first;
+                    }
                     if self.is_eof() {
                         return Some(HtmlToken::EOF);
                     }
@@ -395,7 +1007,7 @@ impl Iterator for HtmlTokenizer {
                     }
 
                     self.reconsume = true;
-                    self.state = State::ScriptData;
+                    self.state = self.text_state.clone();
                     return Some(HtmlToken::Char('<'));
                 }
                 State::ScriptDataEndTagOpen => {
@@ -406,28 +1018,36 @@ impl Iterator for HtmlTokenizer {
                         self.create_tag(false);
                         continue;
                     }
+                    // emit "</" as text
                     self.reconsume = true;
-                    self.state = State::ScriptData;
-                    return Some(HtmlToken::Char('<'));
+                    self.state = State::TemporaryBuffer;
+                    self.buf = "</".to_string();
                 }
                 State::ScriptDataEndTagName => {
-                    if c == '>' {
+                    if c == '>' && self.is_appropriate_end_tag() {
                         self.state = State::Data;
                         return self.take_latest_token();
                     }
+                    if is_whitespace(c) && self.is_appropriate_end_tag() {
+                        self.state = State::BeforeAttributeName;
+                        continue;
+                    }
                     if c.is_ascii_alphabetic() {
                         self.buf.push(c);
-                        self.append_tag_name(c.to_ascii_lowercase());
+                        self.append_tag_name(c);
                         continue;
                     }
+                    // not an end tag. emit "</" and the name as text, and reconsume the
+                    // current char in the text state
+                    self.latest_token = None;
+                    self.reconsume = true;
                     self.state = State::TemporaryBuffer;
                     self.buf = "</".to_string() + &self.buf;
-                    self.buf.push(c);
                 }
                 State::TemporaryBuffer => {
                     self.reconsume = true;
                     if self.buf.is_empty() {
-                        self.state = State::ScriptData;
+                        self.state = self.text_state.clone();
                         continue;
                     }
                     // remove first char
@@ -459,8 +1079,17 @@ pub enum State {
     AttributeValueUnquoted,
     AfterAttributeValueQuoted,
     SelfClosingStartTag,
+    MarkupDeclarationOpen, // after "<!"
+    Doctype,
+    DoctypeName,
+    AfterDoctypeName,
+    Comment,
+    BogusComment,
+    CdataSection,
     ScriptData,             // scripts in <script>
-    ScriptDataLessThanSign, // '<' sign appears in <script>
+    Rcdata,                 // text with character references, e.g. in <title>
+    Rawtext,                // text without character references, e.g. in <style>
+    ScriptDataLessThanSign, // '<' sign appears in ScriptData, Rcdata or Rawtext
     ScriptDataEndTagOpen,
     ScriptDataEndTagName,
     TemporaryBuffer,
@@ -476,6 +1105,7 @@ mod tests {
     fn test_empty() {
         let html = "".to_string();
         let mut tokenizer = HtmlTokenizer::new(html);
+        assert_eq!(tokenizer.next(), Some(HtmlToken::EOF));
         assert!(tokenizer.next().is_none());
     }
 
@@ -578,4 +1208,649 @@ mod tests {
             assert_eq!(Some(e), tokenizer.next());
         }
     }
+
+    #[test]
+    fn test_preserve_tag_case() {
+        let html = "<foreignObject viewBox=x></foreignObject>".to_string();
+        let mut tokenizer = HtmlTokenizer::new(html.clone());
+        tokenizer.preserve_tag_case(true);
+        assert_eq!(
+            tokenizer.next(),
+            Some(HtmlToken::StartTag {
+                tag: "foreignObject".to_string(),
+                self_closing: false,
+                attributes: vec![attribute("viewbox", "x")],
+            })
+        );
+        assert_eq!(
+            tokenizer.next(),
+            Some(HtmlToken::EndTag {
+                tag: "foreignObject".to_string(),
+            })
+        );
+
+        // lowercased by default
+        let mut tokenizer = HtmlTokenizer::new(html);
+        assert_eq!(
+            tokenizer.next(),
+            Some(HtmlToken::StartTag {
+                tag: "foreignobject".to_string(),
+                self_closing: false,
+                attributes: vec![attribute("viewbox", "x")],
+            })
+        );
+    }
+
+    #[test]
+    fn test_switch_to() {
+        let html = "<style>a</b>&amp;</style ><p>".to_string();
+        let mut tokenizer = HtmlTokenizer::new(html.clone());
+        tokenizer.next();
+        tokenizer.switch_to(State::Rawtext);
+        assert_eq!(tokenizer.current_state(), State::Rawtext);
+        assert_eq!(
+            tokenizer.by_ref().take(9).collect::<Vec<_>>(),
+            "a</b>&amp;"[..9]
+                .chars()
+                .map(HtmlToken::Char)
+                .collect::<Vec<_>>()
+        );
+        tokenizer.next();
+        assert_eq!(
+            tokenizer.next(),
+            Some(HtmlToken::EndTag {
+                tag: "style".to_string()
+            })
+        );
+
+        // RCDATA decodes character references
+        let mut tokenizer = HtmlTokenizer::new(html.replace("style", "title"));
+        tokenizer.next();
+        tokenizer.switch_to(State::Rcdata);
+        let text: String = tokenizer
+            .by_ref()
+            .take(6)
+            .map(|t| match t {
+                HtmlToken::Char(c) => c,
+                _ => panic!("unexpected token {:?}", t),
+            })
+            .collect();
+        assert_eq!(text, "a</b>&");
+        assert_eq!(
+            tokenizer.next(),
+            Some(HtmlToken::EndTag {
+                tag: "title".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_character_reference_in_attribute_value() {
+        let html = "<a href=\"a&amp;b\" title='&lt;&#65;&#x42;&gt;' id=c&amp;d class=\"x&y\"></a>"
+            .to_string();
+        let mut tokenizer = HtmlTokenizer::new(html);
+        let attributes = match tokenizer.next() {
+            Some(HtmlToken::StartTag { attributes, .. }) => attributes,
+            t => panic!("unexpected token {:?}", t),
+        };
+
+        let values: Vec<(String, String)> =
+            attributes.iter().map(|a| (a.name(), a.value())).collect();
+        assert_eq!(
+            values,
+            vec![
+                ("href".to_string(), "a&b".to_string()),
+                ("title".to_string(), "<AB>".to_string()),
+                ("id".to_string(), "c&d".to_string()),
+                ("class".to_string(), "x&y".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_max_attributes() {
+        let mut html = "<p".to_string();
+        for i in 0..1000 {
+            html += &format!(" a{}=v{}", i, i);
+        }
+        html += "></p>";
+
+        let mut tokenizer = HtmlTokenizer::new(html);
+        let attributes = match tokenizer.next() {
+            Some(HtmlToken::StartTag { attributes, .. }) => attributes,
+            t => panic!("unexpected token {:?}", t),
+        };
+        assert_eq!(attributes.len(), DEFAULT_MAX_ATTRIBUTES);
+        assert_eq!(attributes[399].name(), "a399");
+        assert_eq!(attributes[399].value(), "v399");
+        assert_eq!(tokenizer.errors().len(), 1);
+        assert_eq!(
+            tokenizer.next(),
+            Some(HtmlToken::EndTag {
+                tag: "p".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_tokenize_all() {
+        let html = "<p>x</p>".to_string();
+        let tokens = HtmlTokenizer::new(html.clone()).tokenize_all();
+
+        let mut tokenizer = HtmlTokenizer::new(html);
+        for t in &tokens {
+            assert_eq!(Some(t.clone()), tokenizer.next());
+        }
+        assert!(tokenizer.next().is_none());
+
+        assert_eq!(
+            tokens,
+            vec![
+                HtmlToken::StartTag {
+                    tag: "p".to_string(),
+                    self_closing: false,
+                    attributes: Vec::new(),
+                },
+                HtmlToken::Char('x'),
+                HtmlToken::EndTag {
+                    tag: "p".to_string(),
+                },
+                HtmlToken::EOF,
+            ]
+        );
+
+        let mut tokenizer = HtmlTokenizer::new("".to_string());
+        assert_eq!(tokenizer.tokenize_all(), vec![HtmlToken::EOF]);
+    }
+
+    fn attribute(name: &str, value: &str) -> Attribute {
+        let mut attr = Attribute::new();
+        name.chars().for_each(|c| attr.add_name(c));
+        value.chars().for_each(|c| attr.add_value(c));
+        attr
+    }
+
+    fn next_start_tag_attributes(tokenizer: &mut HtmlTokenizer) -> Vec<(String, String)> {
+        match tokenizer.next() {
+            Some(HtmlToken::StartTag { attributes, .. }) => {
+                attributes.iter().map(|a| (a.name(), a.value())).collect()
+            }
+            t => panic!("unexpected token {:?}", t),
+        }
+    }
+
+    #[test]
+    fn test_quoted_attribute_value_with_gt() {
+        let html = "<a href=\">\" title='>'>x</a>".to_string();
+        let mut tokenizer = HtmlTokenizer::new(html);
+        assert_eq!(
+            next_start_tag_attributes(&mut tokenizer),
+            vec![
+                ("href".to_string(), ">".to_string()),
+                ("title".to_string(), ">".to_string()),
+            ]
+        );
+        assert_eq!(tokenizer.next(), Some(HtmlToken::Char('x')));
+    }
+
+    #[test]
+    fn test_unquoted_attribute_value_with_gt() {
+        let html = "<a href=>x</a>".to_string();
+        let mut tokenizer = HtmlTokenizer::new(html);
+        assert_eq!(
+            next_start_tag_attributes(&mut tokenizer),
+            vec![("href".to_string(), "".to_string())]
+        );
+        assert_eq!(tokenizer.next(), Some(HtmlToken::Char('x')));
+
+        let html = "<a href=y>x</a>".to_string();
+        let mut tokenizer = HtmlTokenizer::new(html);
+        assert_eq!(
+            next_start_tag_attributes(&mut tokenizer),
+            vec![("href".to_string(), "y".to_string())]
+        );
+        assert_eq!(tokenizer.next(), Some(HtmlToken::Char('x')));
+    }
+
+    #[test]
+    fn test_unquoted_attribute_value_with_eq() {
+        let html = "<a data=a=b id=c>x</a>".to_string();
+        let mut tokenizer = HtmlTokenizer::new(html);
+        assert_eq!(
+            next_start_tag_attributes(&mut tokenizer),
+            vec![
+                ("data".to_string(), "a=b".to_string()),
+                ("id".to_string(), "c".to_string()),
+            ]
+        );
+        assert_eq!(tokenizer.next(), Some(HtmlToken::Char('x')));
+    }
+
+    #[test]
+    fn test_missing_whitespace_between_attributes() {
+        let html = "<p id=\"a\"class='b'>x</p>".to_string();
+        let mut tokenizer = HtmlTokenizer::new(html);
+        assert_eq!(
+            next_start_tag_attributes(&mut tokenizer),
+            vec![
+                ("id".to_string(), "a".to_string()),
+                ("class".to_string(), "b".to_string()),
+            ]
+        );
+        assert_eq!(tokenizer.errors().len(), 1);
+        assert_eq!(tokenizer.next(), Some(HtmlToken::Char('x')));
+    }
+
+    #[test]
+    fn test_attribute_case() {
+        let html = "<A HREF=\"CaseSensitive.png\" Title=MiXed></A>".to_string();
+        let mut tokenizer = HtmlTokenizer::new(html);
+        assert_eq!(
+            tokenizer.next(),
+            Some(HtmlToken::StartTag {
+                tag: "a".to_string(),
+                self_closing: false,
+                attributes: vec![
+                    attribute("href", "CaseSensitive.png"),
+                    attribute("title", "MiXed")
+                ],
+            })
+        );
+    }
+
+    fn collect_chars(tokenizer: HtmlTokenizer) -> String {
+        tokenizer
+            .filter_map(|t| match t {
+                HtmlToken::Char(c) => Some(c),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_character_reference_in_text() {
+        let html =
+            "<p>&notin; &not; &notit; &amp &ampx &lt;&#60;&#x3c; &foo; &nGt;</p>".to_string();
+        let tokenizer = HtmlTokenizer::new(html);
+        assert_eq!(
+            collect_chars(tokenizer),
+            "\u{2209} \u{ac} \u{ac}it; & &x <<< &foo; \u{226b}\u{20d2}"
+        );
+    }
+
+    #[test]
+    fn test_astral_characters() {
+        let html = "<p>\u{1f600}&#x1F600;&#128512;&#xD83D;&#xDE00;</p>".to_string();
+        let tokenizer = HtmlTokenizer::new(html);
+        // a surrogate pair in references is two replacement chars, not one char
+        assert_eq!(
+            collect_chars(tokenizer),
+            "\u{1f600}\u{1f600}\u{1f600}\u{fffd}\u{fffd}"
+        );
+
+        let html = "<a title=\"&#x1F600;\u{1f600}\">".to_string();
+        let mut tokenizer = HtmlTokenizer::new(html);
+        assert_eq!(
+            next_start_tag_attributes(&mut tokenizer),
+            vec![("title".to_string(), "\u{1f600}\u{1f600}".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_legacy_character_reference_in_attribute_value() {
+        let html = "<a href=\"?a=1&amp=2&ampx&amp;b&amp\" title='&not'>".to_string();
+        let mut tokenizer = HtmlTokenizer::new(html);
+        assert_eq!(
+            next_start_tag_attributes(&mut tokenizer),
+            vec![
+                ("href".to_string(), "?a=1&amp=2&ampx&b&".to_string()),
+                ("title".to_string(), "\u{ac}".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_token_range() {
+        let html = "a<p class=x>é</p>".to_string();
+        let mut tokenizer = HtmlTokenizer::new(html);
+        assert_eq!(tokenizer.next(), Some(HtmlToken::Char('a')));
+        assert_eq!(tokenizer.token_range(), 0..1);
+        tokenizer.next();
+        assert_eq!(tokenizer.token_range(), 1..12);
+        assert_eq!(tokenizer.source(tokenizer.token_range()), "<p class=x>");
+        tokenizer.next();
+        assert_eq!(tokenizer.byte_len(tokenizer.token_range()), 2);
+        tokenizer.next();
+        assert_eq!(tokenizer.source(tokenizer.token_range()), "</p>");
+    }
+
+    #[test]
+    fn test_stats() {
+        let html = "<p class=a>ab<br/></p>".to_string();
+        let mut tokenizer = HtmlTokenizer::new(html);
+        assert_eq!(tokenizer.stats(), None);
+
+        tokenizer.set_collect_stats(true);
+        tokenizer.tokenize_all();
+        assert_eq!(
+            tokenizer.stats(),
+            Some(TokenStats {
+                start_tags: 2,
+                end_tags: 1,
+                chars: 2,
+                comments: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn test_doctype() {
+        let html = "<!DOCTYPE html><!doctype HTML PUBLIC \"x\"><!x>a".to_string();
+        let mut tokenizer = HtmlTokenizer::new(html);
+        assert_eq!(
+            tokenizer.tokenize_all(),
+            vec![
+                HtmlToken::Doctype {
+                    name: "html".to_string()
+                },
+                HtmlToken::Doctype {
+                    name: "html".to_string()
+                },
+                HtmlToken::Comment("x".to_string()),
+                HtmlToken::Char('a'),
+                HtmlToken::EOF,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cdata_section() {
+        let html = "<![CDATA[x<y]]]>z".to_string();
+        let mut tokenizer = HtmlTokenizer::new(html);
+        assert_eq!(
+            tokenizer.tokenize_all(),
+            vec![
+                HtmlToken::Char('x'),
+                HtmlToken::Char('<'),
+                HtmlToken::Char('y'),
+                HtmlToken::Char(']'),
+                HtmlToken::Char('z'),
+                HtmlToken::EOF,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cdata_section_in_chunks() {
+        let mut tokenizer = HtmlTokenizer::new(String::new());
+        let mut tokens = Vec::new();
+        for chunk in ["<![CD", "ATA[x]", "]", ">y"] {
+            tokenizer.push_str(chunk);
+            tokens.extend(tokenizer.by_ref());
+        }
+        assert_eq!(tokens, vec![HtmlToken::Char('x'), HtmlToken::Char('y')]);
+    }
+
+    #[test]
+    fn test_whitespace_in_tag() {
+        let html = "<a\thref=x\ny\r\nid='z'\n>".to_string();
+        let mut tokenizer = HtmlTokenizer::new(html);
+        assert_eq!(
+            next_start_tag_attributes(&mut tokenizer),
+            vec![
+                ("href".to_string(), "x".to_string()),
+                ("y".to_string(), "".to_string()),
+                ("id".to_string(), "z".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_eof_of_one_char_input() {
+        let mut tokenizer = HtmlTokenizer::new("a".to_string());
+        assert_eq!(tokenizer.next(), Some(HtmlToken::Char('a')));
+        assert!(!tokenizer.is_eof());
+        assert_eq!(tokenizer.next(), Some(HtmlToken::EOF));
+        assert!(tokenizer.is_eof());
+        assert_eq!(tokenizer.next(), None);
+    }
+
+    #[test]
+    fn test_max_input_size() {
+        let html = "<p>abc</p>".to_string();
+        assert!(HtmlTokenizer::try_with_max_input_size(html.clone(), html.len()).is_ok());
+        assert_eq!(
+            HtmlTokenizer::try_with_max_input_size(html.clone(), html.len() - 1),
+            Err(Error::UnexpectedInput(
+                "Input is too large: 10 bytes (max 9 bytes)".to_string()
+            ))
+        );
+        // the limit is in bytes, not chars
+        assert!(HtmlTokenizer::try_with_max_input_size("\u{e9}".to_string(), 1).is_err());
+
+        let mut tokenizer = HtmlTokenizer::try_new(html).expect("input is small enough");
+        assert_eq!(tokenizer.tokenize_all().len(), 6);
+    }
+
+    #[test]
+    fn test_ampersand_at_end_of_attribute_value() {
+        for html in ["<a href=\"x&\">", "<a href='x&'>", "<a href=x&>"] {
+            let mut tokenizer = HtmlTokenizer::new(html.to_string());
+            assert_eq!(
+                next_start_tag_attributes(&mut tokenizer),
+                vec![("href".to_string(), "x&".to_string())],
+                "{}",
+                html
+            );
+        }
+
+        // `&` is the last char of the input so far
+        let mut tokenizer = HtmlTokenizer::new(String::new());
+        tokenizer.push_str("<a href=\"x&");
+        assert_eq!(tokenizer.next(), None);
+        tokenizer.push_str("\">");
+        assert_eq!(
+            next_start_tag_attributes(&mut tokenizer),
+            vec![("href".to_string(), "x&".to_string())]
+        );
+
+        // the input ends right after `&`
+        let mut tokenizer = HtmlTokenizer::new(String::new());
+        tokenizer.push_str("<a href=\"x&");
+        assert_eq!(tokenizer.next(), None);
+        tokenizer.close();
+        assert_eq!(tokenizer.next(), Some(HtmlToken::EOF));
+        assert_eq!(tokenizer.next(), None);
+    }
+
+    #[test]
+    fn test_current_state() {
+        let mut tokenizer = HtmlTokenizer::new(String::new());
+        assert_eq!(tokenizer.current_state(), State::Data);
+        tokenizer.push_str("<div ");
+        assert_eq!(tokenizer.next(), None);
+        assert_eq!(tokenizer.current_state(), State::BeforeAttributeName);
+        tokenizer.push_str("id=x");
+        assert_eq!(tokenizer.next(), None);
+        assert_eq!(tokenizer.current_state(), State::AttributeValueUnquoted);
+        tokenizer.push_str(">");
+        assert!(tokenizer.next().is_some());
+        assert_eq!(tokenizer.current_state(), State::Data);
+    }
+
+    #[test]
+    fn test_eof_after_close() {
+        let mut tokenizer = HtmlTokenizer::new(String::new());
+        tokenizer.push_str("a");
+        assert_eq!(tokenizer.next(), Some(HtmlToken::Char('a')));
+        assert_eq!(tokenizer.next(), None);
+        tokenizer.close();
+        assert_eq!(tokenizer.next(), Some(HtmlToken::EOF));
+        assert_eq!(tokenizer.next(), None);
+    }
+
+    #[test]
+    fn test_truncated_input() {
+        let html = "<!DOCTYPE html><a href=\"x\" id='y' class=z b/>t&amp;<![CDATA[c]]></a>";
+        for (i, _) in html.char_indices() {
+            let mut tokenizer = HtmlTokenizer::new(html[..i].to_string());
+            let tokens = tokenizer.tokenize_all();
+            assert_eq!(tokens.last(), Some(&HtmlToken::EOF));
+            assert_eq!(tokenizer.next(), None);
+        }
+    }
+
+    #[test]
+    fn test_attribute_without_value() {
+        let mut tokenizer = HtmlTokenizer::new("<input a disabled>x".to_string());
+        let mut a = Attribute::new();
+        a.add_name('a');
+        let mut disabled = Attribute::new();
+        "disabled".chars().for_each(|c| disabled.add_name(c));
+        let expected = [
+            HtmlToken::StartTag {
+                tag: "input".to_string(),
+                self_closing: false,
+                attributes: vec![a, disabled],
+            },
+            HtmlToken::Char('x'),
+            HtmlToken::EOF,
+        ];
+        for e in expected {
+            assert_eq!(Some(e), tokenizer.next());
+        }
+        assert!(tokenizer.errors().is_empty());
+    }
+
+    #[test]
+    fn test_end_tag_with_attributes_or_solidus() {
+        for (html, tag, error) in [
+            (
+                "</p class=a>x",
+                "p",
+                "Attributes in an end tag </p> are dropped.",
+            ),
+            (
+                "</br/>x",
+                "br",
+                "Self-closing end tag </br/> is treated as </br>.",
+            ),
+            (
+                "</x</pre>x",
+                "x<",
+                "Self-closing end tag </x</> is treated as </x<>.",
+            ),
+        ] {
+            let mut tokenizer = HtmlTokenizer::new(html.to_string());
+            let expected = [
+                HtmlToken::EndTag {
+                    tag: tag.to_string(),
+                },
+                HtmlToken::Char('x'),
+                HtmlToken::EOF,
+            ];
+            for e in expected {
+                assert_eq!(Some(e), tokenizer.next(), "{}", html);
+            }
+            assert!(
+                tokenizer
+                    .errors()
+                    .contains(&Error::UnexpectedInput(error.to_string())),
+                "{}",
+                html
+            );
+        }
+    }
+
+    #[test]
+    fn test_eof_in_tag() {
+        for html in ["a<div", "a<div cl", "a<div class=\"x y"] {
+            let mut tokenizer = HtmlTokenizer::new(html.to_string());
+            assert_eq!(tokenizer.next(), Some(HtmlToken::Char('a')));
+            assert_eq!(tokenizer.next(), Some(HtmlToken::EOF), "{}", html);
+            // the incomplete tag is discarded
+            assert!(tokenizer.latest_token.is_none(), "{}", html);
+            assert_eq!(tokenizer.next(), None, "{}", html);
+            assert_eq!(
+                tokenizer.errors(),
+                vec![Error::UnexpectedInput(
+                    "Unexpected end of file in a tag".to_string()
+                )]
+            );
+        }
+    }
+
+    #[test]
+    fn test_bogus_comment() {
+        let html = "<?php ?><p>a</p><?x".to_string();
+        let mut tokenizer = HtmlTokenizer::new(html);
+        assert_eq!(
+            tokenizer.tokenize_all(),
+            vec![
+                HtmlToken::Comment("?php ?".to_string()),
+                HtmlToken::StartTag {
+                    tag: "p".to_string(),
+                    self_closing: false,
+                    attributes: Vec::new(),
+                },
+                HtmlToken::Char('a'),
+                HtmlToken::EndTag {
+                    tag: "p".to_string(),
+                },
+                HtmlToken::Comment("?x".to_string()),
+                HtmlToken::EOF,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_comment() {
+        let html = "<!-- a - b -- c --><!----><!--><!---><!--x".to_string();
+        let mut tokenizer = HtmlTokenizer::new(html);
+        assert_eq!(
+            tokenizer.tokenize_all(),
+            vec![
+                HtmlToken::Comment(" a - b -- c ".to_string()),
+                HtmlToken::Comment("".to_string()),
+                HtmlToken::Comment("".to_string()),
+                HtmlToken::Comment("".to_string()),
+                HtmlToken::Comment("x".to_string()),
+                HtmlToken::EOF,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_comment_in_chunks() {
+        let mut tokenizer = HtmlTokenizer::new(String::new());
+        let mut tokens = Vec::new();
+        for chunk in ["<!", "-", "-a-", "-", ">b"] {
+            tokenizer.push_str(chunk);
+            tokens.extend(tokenizer.by_ref());
+        }
+        assert_eq!(
+            tokens,
+            vec![HtmlToken::Comment("a".to_string()), HtmlToken::Char('b')]
+        );
+    }
+
+    #[test]
+    fn test_token_is_whitespace() {
+        assert!(HtmlToken::Char(' ').is_whitespace());
+        assert!(HtmlToken::Char('\t').is_whitespace());
+        assert!(HtmlToken::Char('\n').is_whitespace());
+        assert!(!HtmlToken::Char('a').is_whitespace());
+        assert!(!HtmlToken::Char('\u{a0}').is_whitespace());
+        assert!(!HtmlToken::EOF.is_whitespace());
+    }
+
+    #[test]
+    fn test_reconsume_guard() {
+        // inputs which go through many reconsuming transitions
+        let html = "<a b c=d e='f'g=\"h\"/><!-- x --><!--><!DOCTYPE html><? pi ?>&amp &#x41; &zz; <1 a<b/ c>";
+        let mut tokenizer = HtmlTokenizer::new(html.to_string());
+        while tokenizer.next().is_some() {
+            assert!(tokenizer.reconsume_count < MAX_CONSECUTIVE_RECONSUMES / 4);
+        }
+    }
 }
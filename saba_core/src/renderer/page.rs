@@ -1,17 +1,30 @@
 use crate::browser::Browser;
-use crate::renderer::dom::node::Window;
-use crate::renderer::html::parser::HtmlParser;
-use crate::renderer::html::token::HtmlTokenizer;
+use crate::renderer::dom::node::{ElementKind, Window};
 use crate::http::HttpResponse;
+use crate::parse_html;
+use crate::renderer::html::parser::HtmlParser;
+use crate::renderer::html::token::HtmlToken;
 use crate::utils::convert_dom_to_string;
+use alloc::format;
 use alloc::string::{String, ToString};
 use alloc::rc::{Rc, Weak};
+use alloc::vec::Vec;
 use core::cell::RefCell;
 
+/// A `<script>` in the page. Scripts are not executed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScriptRef {
+    /// `<script src>` with the resolved url.
+    External(String),
+    /// `<script>` without `src` with its code.
+    Inline(String),
+}
+
 #[derive(Debug, Clone)]
 pub struct Page {
     browser: Weak<RefCell<Browser>>,
     frame: Option<Rc<RefCell<Window>>>,
+    url: String,
 }
 
 impl Page {
@@ -19,6 +32,7 @@ impl Page {
         Self {
             browser: Weak::new(),
             frame: None,
+            url: "".to_string(),
         }
     }
 
@@ -26,8 +40,88 @@ impl Page {
         self.browser = browser;
     }
 
+    pub fn set_url(&mut self, url: String) {
+        self.url = url;
+    }
+
+    pub fn url(&self) -> String {
+        self.url.clone()
+    }
+
+    /// Resolves `href` against the document base url, i.e. the `href` of the first
+    /// `<base>` resolved against the page url, or the page url if there is none. This
+    /// implementation does not normalize dot segments such as `..`.
+    pub fn resolve_href(&self, href: &str) -> String {
+        let base = match self.frame.as_ref().and_then(|f| f.borrow().base_url()) {
+            Some(base) => resolve_url(&self.url, &base),
+            None => self.url.clone(),
+        };
+        resolve_url(&base, href)
+    }
+
+    /// Returns the title of the page, or an empty string if there is no `<title>`.
+    pub fn title(&self) -> String {
+        match &self.frame {
+            Some(frame) => frame.borrow().title(),
+            None => "".to_string(),
+        }
+    }
+
+    /// Returns the resolved urls of all `<a href>` in the page.
+    pub fn links(&self) -> Vec<String> {
+        let frame = match &self.frame {
+            Some(frame) => frame,
+            None => return Vec::new(),
+        };
+        let document = frame.borrow().document();
+        let links = document
+            .borrow()
+            .descendants()
+            .filter(|n| n.borrow().is_element(ElementKind::A))
+            .filter_map(|n| n.borrow().get_element()?.get_attribute("href"))
+            .map(|href| self.resolve_href(&href))
+            .collect();
+        links
+    }
+
+    /// Returns all `<script>` in the page in document order.
+    pub fn scripts(&self) -> Vec<ScriptRef> {
+        let frame = match &self.frame {
+            Some(frame) => frame,
+            None => return Vec::new(),
+        };
+        let document = frame.borrow().document();
+        let scripts = document
+            .borrow()
+            .descendants()
+            .filter(|n| n.borrow().is_element(ElementKind::Script))
+            .map(|n| {
+                let src = n
+                    .borrow()
+                    .get_element()
+                    .and_then(|e| e.get_attribute("src"));
+                match src {
+                    Some(src) => ScriptRef::External(self.resolve_href(&src)),
+                    None => ScriptRef::Inline(n.borrow().text_content()),
+                }
+            })
+            .collect();
+        scripts
+    }
+
+    /// Drops the current DOM tree. To reload, the browser calls this, fetches
+    /// `url()` again, and passes the new response to `receive_response`.
+    pub fn clear_frame(&mut self) {
+        self.frame = None;
+    }
+
     pub fn receive_response(&mut self, response: HttpResponse) -> String {
-        self.create_frame(response.body());
+        let body = body_with_content_length(&response);
+        if is_plain_text(&response) {
+            self.create_text_frame(body);
+        } else {
+            self.create_frame(body);
+        }
 
         // for debug
         if let Some(frame) = &self.frame {
@@ -39,8 +133,287 @@ impl Page {
     }
 
     fn create_frame(&mut self, html: String)  {
-        let tokenizer= HtmlTokenizer::new(html);
-        let frame = HtmlParser::new(tokenizer).construct_tree();
-        self.frame = Some(frame);
+        self.frame = Some(parse_html(html));
+    }
+
+    /// Creates `<html><body><pre>text</pre></body></html>` without parsing `text`
+    /// as html.
+    fn create_text_frame(&mut self, text: String) {
+        let mut tokens: Vec<HtmlToken> = ["html", "body", "pre"]
+            .iter()
+            .map(|tag| HtmlToken::StartTag {
+                tag: tag.to_string(),
+                self_closing: false,
+                attributes: Vec::new(),
+            })
+            .collect();
+        tokens.extend(text.chars().map(HtmlToken::Char));
+        tokens.push(HtmlToken::EOF);
+        self.frame = Some(HtmlParser::from_tokens(tokens).construct_tree());
+    }
+}
+
+/// https://url.spec.whatwg.org/#scheme-state
+/// Returns true if `url` starts with a scheme followed by `:` before any `/`, `?`,
+/// or `#`.
+fn has_scheme(url: &str) -> bool {
+    let scheme = match url.find([':', '/', '?', '#']) {
+        Some(i) if url[i..].starts_with(':') => &url[..i],
+        _ => return false,
+    };
+    let mut chars = scheme.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+}
+
+fn resolve_url(base: &str, href: &str) -> String {
+    if has_scheme(href) {
+        // absolute url, e.g. `http://host/` or `mailto:a@b`
+        return href.to_string();
+    }
+
+    // strip fragment and search parts
+    let url = base.split('#').next().unwrap_or("");
+    if let Some(fragment) = href.strip_prefix('#') {
+        return format!("{}#{}", url, fragment);
+    }
+    let url = url.split('?').next().unwrap_or("");
+    if href.starts_with('?') {
+        return format!("{}{}", url, href);
+    }
+
+    let path_start = match url.find("://") {
+        Some(i) => url[i + 3..]
+            .find('/')
+            .map(|j| i + 3 + j)
+            .unwrap_or(url.len()),
+        None => 0,
+    };
+    let origin = &url[..path_start];
+    if let Some(path) = href.strip_prefix("//") {
+        // scheme-relative url
+        let scheme = origin.split("://").next().unwrap_or("http");
+        return format!("{}://{}", scheme, path);
+    }
+    if href.starts_with('/') {
+        return format!("{}{}", origin, href);
+    }
+    if href.is_empty() {
+        return base.to_string();
+    }
+
+    // replace the last path segment
+    let path = &url[path_start..];
+    let dir = match path.rfind('/') {
+        Some(i) => &path[..i + 1],
+        None => "/",
+    };
+    format!("{}{}{}", origin, dir, href)
+}
+
+fn is_plain_text(response: &HttpResponse) -> bool {
+    match response.header_value("Content-Type") {
+        // ignore parameters such as charset
+        Ok(value) => value
+            .split(';')
+            .next()
+            .is_some_and(|t| t.trim().eq_ignore_ascii_case("text/plain")),
+        Err(_) => false,
+    }
+}
+
+/// Returns the body truncated to `Content-Length` bytes so that trailing garbage is
+/// not parsed. A body shorter than the declared length, e.g. because the connection
+/// was closed early, is used as it is.
+fn body_with_content_length(response: &HttpResponse) -> String {
+    let mut body = response.body();
+    let length = response
+        .header_value("Content-Length")
+        .ok()
+        .and_then(|v| v.trim().parse::<usize>().ok());
+    if let Some(mut length) = length {
+        if length < body.len() {
+            // don't split a multibyte char
+            while !body.is_char_boundary(length) {
+                length -= 1;
+            }
+            body.truncate(length);
+        }
+    }
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::dom::node::Node;
+    use crate::renderer::dom::testing::NodeBuilder;
+
+    fn page(url: &str) -> Page {
+        let mut page = Page::new();
+        page.set_url(url.to_string());
+        page
+    }
+
+    #[test]
+    fn test_resolve_href() {
+        let page = page("http://host/dir/page");
+        assert_eq!(page.url(), "http://host/dir/page");
+        assert_eq!(page.resolve_href("/a"), "http://host/a");
+        assert_eq!(page.resolve_href("b"), "http://host/dir/b");
+        assert_eq!(page.resolve_href("http://other/c"), "http://other/c");
+        assert_eq!(page.resolve_href("//other/d"), "http://other/d");
+        assert_eq!(page.resolve_href("?q=1"), "http://host/dir/page?q=1");
+        assert_eq!(page.resolve_href("#top"), "http://host/dir/page#top");
+        assert_eq!(
+            page.resolve_href("next?to=http://x"),
+            "http://host/dir/next?to=http://x"
+        );
+        assert_eq!(page.resolve_href("mailto:a@b"), "mailto:a@b");
+        assert_eq!(
+            page.resolve_href("javascript:void(0)"),
+            "javascript:void(0)"
+        );
+    }
+
+    #[test]
+    fn test_resolve_href_without_path() {
+        let page = page("http://host:8000");
+        assert_eq!(page.resolve_href("a"), "http://host:8000/a");
+        assert_eq!(page.resolve_href("/b"), "http://host:8000/b");
+    }
+
+    #[test]
+    fn test_links() {
+        let mut page = page("http://host/dir/page?x=1");
+        page.create_frame(
+            "<html><body><a href=\"/a\">a</a><p><a href=b>b</a></p><a>c</a></body></html>"
+                .to_string(),
+        );
+        assert_eq!(page.links(), ["http://host/a", "http://host/dir/b"]);
+    }
+
+    #[test]
+    fn test_base_href() {
+        let mut page = page("http://host/dir/page");
+        page.create_frame(
+            "<html><head><base href=\"http://cdn/\"></head><body><img src=img.png></body></html>"
+                .to_string(),
+        );
+        assert_eq!(page.resolve_href("img.png"), "http://cdn/img.png");
+
+        // a relative base is resolved against the page url
+        page.create_frame("<head><base href=\"/static/\"></head><a href=a.css>a</a>".to_string());
+        assert_eq!(page.links(), ["http://host/static/a.css"]);
+    }
+
+    #[test]
+    fn test_scripts() {
+        let mut page = page("http://host/dir/page");
+        page.create_frame(
+            "<html><head><script src=\"app.js\"></script></head><body><script>let a = 1;</script></body></html>"
+                .to_string(),
+        );
+        assert_eq!(
+            page.scripts(),
+            [
+                ScriptRef::External("http://host/dir/app.js".to_string()),
+                ScriptRef::Inline("let a = 1;".to_string()),
+            ]
+        );
+    }
+
+    fn response(body: &str) -> HttpResponse {
+        HttpResponse::new(format!(
+            "HTTP/1.1 200 OK\nContent-Type: text/html\n\n{}",
+            body
+        ))
+        .expect("failed to parse the response")
+    }
+
+    fn response_with_length(length: usize, body: &str) -> HttpResponse {
+        HttpResponse::new(format!(
+            "HTTP/1.1 200 OK\nContent-Length: {}\n\n{}",
+            length, body
+        ))
+        .expect("failed to parse the response")
+    }
+
+    #[test]
+    fn test_content_length() {
+        let body = "<title>a</title>garbage<title>b</title>";
+        let truncated = response_with_length("<title>a</title>".len(), body);
+        assert_eq!(body_with_content_length(&truncated), "<title>a</title>");
+
+        // the body is shorter than declared
+        let short = response_with_length(100, body);
+        assert_eq!(body_with_content_length(&short), body);
+
+        // a multibyte char is not split
+        let multibyte = response_with_length(2, "a\u{e9}");
+        assert_eq!(body_with_content_length(&multibyte), "a");
+
+        // no or invalid Content-Length
+        assert_eq!(body_with_content_length(&response("abc")), "abc");
+        let raw = "HTTP/1.1 200 OK\nContent-Length: x\n\nabc".to_string();
+        let invalid = HttpResponse::new(raw).expect("failed to parse the response");
+        assert_eq!(body_with_content_length(&invalid), "abc");
+
+        // header names are case-insensitive
+        let raw = format!("HTTP/1.1 200 OK\ncontent-length: 16\n\n{}", body);
+        let lowercase = HttpResponse::new(raw).expect("failed to parse the response");
+        assert_eq!(body_with_content_length(&lowercase), "<title>a</title>");
+
+        let mut page = page("http://host/");
+        page.receive_response(response_with_length(16, body));
+        assert_eq!(page.title(), "a");
+    }
+
+    #[test]
+    fn test_plain_text() {
+        let text = "<p>not html</p>\n  &amp; a < b";
+        let raw = format!(
+            "HTTP/1.1 200 OK\nContent-Type: text/plain; charset=utf-8\n\n{}",
+            text
+        );
+        let mut page = page("http://host/a.txt");
+        page.receive_response(HttpResponse::new(raw).expect("failed to parse the response"));
+
+        let frame = page.frame.clone().expect("frame should be created");
+        let document = frame.borrow().document();
+        let expected = NodeBuilder::document()
+            .child(
+                NodeBuilder::element("html")
+                    .child(NodeBuilder::element("head"))
+                    .child(
+                        NodeBuilder::element("body")
+                            .child(NodeBuilder::element("pre").child(NodeBuilder::text(text))),
+                    ),
+            )
+            .build();
+        assert!(Node::tree_eq(&expected, &document));
+
+        // header names are case-insensitive
+        let raw = format!("HTTP/1.1 200 OK\ncontent-type: text/plain\n\n{}", text);
+        assert!(is_plain_text(
+            &HttpResponse::new(raw).expect("failed to parse the response")
+        ));
+    }
+
+    #[test]
+    fn test_reload() {
+        let mut page = page("http://host/page");
+        page.receive_response(response("<title>a</title><a href=a>a</a>"));
+        assert_eq!(page.title(), "a");
+        assert_eq!(page.links(), ["http://host/a"]);
+
+        page.clear_frame();
+        assert_eq!(page.title(), "");
+        assert!(page.links().is_empty());
+
+        page.receive_response(response("<title>b</title><a href=b>b</a>"));
+        assert_eq!(page.url(), "http://host/page");
+        assert_eq!(page.title(), "b");
+        assert_eq!(page.links(), ["http://host/b"]);
     }
 }
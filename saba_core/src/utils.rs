@@ -1,4 +1,5 @@
-use crate::renderer::dom::node::Node;
+use crate::renderer::dom::node::{Node, NodeKind};
+use alloc::collections::BTreeSet;
 use alloc::format;
 use alloc::rc::Rc;
 use alloc::string::{String, ToString};
@@ -6,7 +7,8 @@ use core::cell::RefCell;
 
 pub fn convert_dom_to_string(root: &Option<Rc<RefCell<Node>>>) -> String {
     let mut result = "\n".to_string();
-    convert_dom_to_string_internal(root, 0, &mut result);
+    let mut visited = BTreeSet::new();
+    convert_dom_to_string_internal(root, 0, &mut result, &mut visited);
     result
 }
 
@@ -14,15 +16,76 @@ fn convert_dom_to_string_internal(
     node: &Option<Rc<RefCell<Node>>>,
     depth: usize,
     result: &mut String,
+    visited: &mut BTreeSet<*const RefCell<Node>>,
 ) {
     match node {
         Some(n) => {
+            if !visited.insert(Rc::as_ptr(n)) {
+                // the tree is broken. stop here not to loop forever
+                result.push_str(&"  ".repeat(depth));
+                result.push_str("warning: cycle detected\n");
+                return;
+            }
+            if n.borrow().kind() == NodeKind::DocumentFragment {
+                // a fragment is serialized as just its children
+                convert_dom_to_string_internal(&n.borrow().first_child(), depth, result, visited);
+                return;
+            }
             result.push_str(&"  ".repeat(depth));
-            result.push_str(&format!("{:?}", n.borrow().kind()));
+            match n.borrow().kind() {
+                NodeKind::Doctype { name } => result.push_str(&format!("<!DOCTYPE {}>", name)),
+                kind => result.push_str(&format!("{:?}", kind)),
+            }
             result.push('\n');
-            convert_dom_to_string_internal(&n.borrow().first_child(), depth + 1, result);
-            convert_dom_to_string_internal(&n.borrow().next_sibling(), depth, result);
+            convert_dom_to_string_internal(&n.borrow().first_child(), depth + 1, result, visited);
+            convert_dom_to_string_internal(&n.borrow().next_sibling(), depth, result, visited);
         }
         None => (),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::dom::testing::NodeBuilder;
+
+    #[test]
+    fn test_cycle() {
+        let parent = NodeBuilder::element("p")
+            .child(NodeBuilder::text("a"))
+            .child(NodeBuilder::text("b"))
+            .build();
+        let a = parent.borrow().first_child().expect("a should exist");
+        let b = a.borrow().next_sibling().expect("b should exist");
+        b.borrow_mut().set_next_sibling(Some(a.clone()));
+
+        let result = convert_dom_to_string(&Some(parent.clone()));
+        assert!(result.ends_with("  warning: cycle detected\n"));
+        assert_eq!(result.lines().count(), 5);
+
+        b.borrow_mut().set_next_sibling(None);
+    }
+
+    #[test]
+    fn test_fragment() {
+        let fragment = NodeBuilder::fragment()
+            .child(NodeBuilder::element("p").child(NodeBuilder::text("a")))
+            .build();
+
+        let result = convert_dom_to_string(&Some(fragment));
+        assert_eq!(result.lines().count(), 3);
+        assert!(result.starts_with("\nElement("));
+        assert!(result.ends_with("\n  Text(\"a\")\n"));
+    }
+
+    #[test]
+    fn test_doctype() {
+        let document = NodeBuilder::document()
+            .child(NodeBuilder::doctype("html"))
+            .child(NodeBuilder::element("html"))
+            .build();
+
+        let result = convert_dom_to_string(&Some(document));
+        assert_eq!(result.lines().nth(2), Some("  <!DOCTYPE html>"));
+    }
+}
@@ -0,0 +1,128 @@
+//! Parses whole documents through `Page`, `HtmlParser`, and the DOM together.
+use saba_core::http::HttpResponse;
+use saba_core::parse_html;
+use saba_core::renderer::dom::node::ElementKind;
+use saba_core::renderer::page::Page;
+
+const ARTICLE: &str = include_str!("data/article.html");
+const MALFORMED: &str = include_str!("data/malformed.html");
+
+fn load(url: &str, html: &str) -> Page {
+    let response = HttpResponse::new(format!(
+        "HTTP/1.1 200 OK\nContent-Type: text/html\n\n{}",
+        html
+    ))
+    .expect("failed to parse the response");
+    let mut page = Page::new();
+    page.set_url(url.to_string());
+    page.receive_response(response);
+    page
+}
+
+#[test]
+fn test_article_title() {
+    let page = load("http://example.com/dir/index.html", ARTICLE);
+    assert_eq!(page.title(), "Saba & Friends: A Tiny Browser");
+}
+
+#[test]
+fn test_article_links() {
+    let page = load("http://example.com/dir/index.html", ARTICLE);
+    let links = page.links();
+    assert_eq!(links.len(), 9);
+    assert_eq!(links[0], "http://example.com/");
+    assert_eq!(links[3], "http://example.com/dir/index.html#content");
+    assert_eq!(links[4], "http://example.com/dir/intro.html");
+    assert_eq!(links[7], "http://cdn.example.com/file");
+    assert_eq!(links[8], "http://example.com/license");
+}
+
+#[test]
+fn test_article_elements() {
+    let window = parse_html(ARTICLE.to_string());
+    let window = window.borrow();
+
+    let deep = window.get_elements_by_name("deep");
+    assert_eq!(deep.len(), 1);
+    let p = deep[0].borrow();
+    assert!(p.is_element(ElementKind::P));
+    assert_eq!(p.inner_text(), "The deeply nested paragraph");
+    let ancestors = p
+        .ancestors()
+        .filter(|n| n.borrow().is_element(ElementKind::Div));
+    assert_eq!(ancestors.count(), 4);
+
+    let text_of = |kind: ElementKind| -> Vec<String> {
+        window
+            .all_elements()
+            .iter()
+            .filter(|n| n.borrow().is_element(kind.clone()))
+            .map(|n| n.borrow().inner_text())
+            .collect()
+    };
+    assert_eq!(text_of(ElementKind::H1), ["Saba <Browser>"]);
+    assert_eq!(
+        text_of(ElementKind::H2),
+        ["Introduction", "Images", "Forms", "More reading"]
+    );
+    assert_eq!(text_of(ElementKind::Th), ["Feature", "Status"]);
+    assert_eq!(text_of(ElementKind::Td).len(), 6);
+    assert_eq!(text_of(ElementKind::Option), ["One", "Two", "Three"]);
+
+    let alts: Vec<_> = window
+        .all_elements()
+        .iter()
+        .filter_map(|n| n.borrow().get_element())
+        .filter(|e| e.kind() == ElementKind::Img)
+        .filter_map(|e| e.get_attribute("alt"))
+        .collect();
+    assert_eq!(alts, ["Saba logo", "Screenshot"]);
+
+    assert_eq!(
+        window.get_elements_by_class_name("note")[0]
+            .borrow()
+            .inner_text(),
+        "This page exercises \"entities\", comments, and relative links."
+    );
+    assert!(text_of(ElementKind::P).contains(&"Characters: © é A B ∉ &".to_string()));
+    assert_eq!(window.style_sheets().len(), 1);
+    // tags in a script are not parsed
+    let scripts: Vec<_> = window
+        .all_elements()
+        .iter()
+        .filter(|n| n.borrow().is_element(ElementKind::Script))
+        .map(|n| n.borrow().text_content())
+        .collect();
+    assert_eq!(scripts.len(), 1);
+    assert!(scripts[0].contains("<p>not a paragraph</p>"));
+    assert!(!text_of(ElementKind::P).iter().any(|t| t.contains("not a")));
+}
+
+#[test]
+fn test_malformed_document() {
+    let page = load("http://example.com/", MALFORMED);
+    assert_eq!(page.title(), "Broken <b>page");
+    assert_eq!(
+        page.links(),
+        ["http://example.com/a", "http://example.com/b"]
+    );
+
+    let window = parse_html(MALFORMED.to_string());
+    let window = window.borrow();
+    let h2 = window
+        .all_elements()
+        .into_iter()
+        .find(|n| n.borrow().is_element(ElementKind::H2))
+        .expect("h2 should be inserted");
+    assert_eq!(h2.borrow().text_content(), "subheading");
+}
+
+#[test]
+fn test_truncated_documents() {
+    // the input can end anywhere
+    for html in [ARTICLE, MALFORMED] {
+        for (i, _) in html.char_indices() {
+            parse_html(html[..i].to_string());
+        }
+    }
+}
@@ -0,0 +1,97 @@
+#!/usr/bin/env python3
+# Generates saba_core/src/renderer/html/entities.rs from the named character
+# references of the HTML standard (https://html.spec.whatwg.org/entities.json).
+# Python's html.entities.html5 has the same table.
+#
+# usage: python3 tools/gen_entities.py > saba_core/src/renderer/html/entities.rs
+
+from html.entities import html5
+
+print("""//! https://html.spec.whatwg.org/multipage/named-characters.html
+//! This file is generated by tools/gen_entities.py. Do not edit it by hand.
+
+/// The longest name of named character references including `;`.
+pub const MAX_NAME_LEN: usize = %d;
+
+/// Named character references sorted by name. Names don't have the leading `&`.
+/// Some legacy names are also listed without the trailing `;`.
+pub static NAMED_CHARACTER_REFERENCES: &[(&str, &str)] = &[""" % max(len(k) for k in html5))
+
+for name in sorted(html5):
+    value = "".join("\\u{%x}" % ord(c) for c in html5[name])
+    print('    ("%s", "%s"),' % (name, value))
+
+print("""];
+
+/// Returns the replacement chars of a named character reference.
+pub fn lookup(name: &str) -> Option<&'static str> {
+    NAMED_CHARACTER_REFERENCES
+        .binary_search_by(|(n, _)| (*n).cmp(name))
+        .ok()
+        .map(|i| NAMED_CHARACTER_REFERENCES[i].1)
+}
+
+/// Finds the longest named character reference at the beginning of `input`,
+/// which should not include the leading `&`. Returns the matched name and its
+/// replacement chars.
+pub fn lookup_longest(input: &[char]) -> Option<(&'static str, &'static str)> {
+    let mut candidate = [0u8; MAX_NAME_LEN];
+    let mut len = 0;
+    for c in input.iter().take(MAX_NAME_LEN) {
+        if !c.is_ascii_alphanumeric() && *c != ';' {
+            break;
+        }
+        candidate[len] = *c as u8;
+        len += 1;
+        if *c == ';' {
+            break;
+        }
+    }
+
+    while len > 0 {
+        // candidate only has ascii chars
+        let name = core::str::from_utf8(&candidate[..len]).ok()?;
+        if let Ok(i) = NAMED_CHARACTER_REFERENCES.binary_search_by(|(n, _)| (*n).cmp(name)) {
+            return Some(NAMED_CHARACTER_REFERENCES[i]);
+        }
+        len -= 1;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn test_sorted() {
+        assert!(NAMED_CHARACTER_REFERENCES
+            .windows(2)
+            .all(|w| w[0].0 < w[1].0));
+    }
+
+    #[test]
+    fn test_lookup() {
+        assert_eq!(lookup("amp;"), Some("&"));
+        assert_eq!(lookup("amp"), Some("&"));
+        assert_eq!(lookup("notin;"), Some("\\u{2209}"));
+        assert_eq!(lookup("foo;"), None);
+    }
+
+    #[test]
+    fn test_lookup_longest() {
+        let input: Vec<char> = "notin;x".chars().collect();
+        assert_eq!(lookup_longest(&input), Some(("notin;", "\\u{2209}")));
+
+        let input: Vec<char> = "not;x".chars().collect();
+        assert_eq!(lookup_longest(&input), Some(("not;", "\\u{ac}")));
+
+        // `not` is a prefix of `notit;` which is not a reference
+        let input: Vec<char> = "notit;".chars().collect();
+        assert_eq!(lookup_longest(&input), Some(("not", "\\u{ac}")));
+
+        let input: Vec<char> = "foo;".chars().collect();
+        assert_eq!(lookup_longest(&input), None);
+    }
+}""")